@@ -0,0 +1,82 @@
+//Average colouring. A value in 0..1 is taken at every iteration and the pixel is coloured by its
+//mean over the orbit. The average with and without the last iteration is blended by the
+//fraction from the smooth iteration count so there are no bands where the count steps
+pub const AVERAGES: [&str; 2] = ["stripe", "triangle"];
+pub const DEFAULT_STRIPE_DENSITY: f64 = 5.0;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Average {
+    //0.5 + 0.5 * sin(density * arg z)
+    Stripe(f64),
+    //Where |z^n + c| falls between the smallest and largest it could be, ||z^n| - |c|| and
+    //|z^n| + |c|
+    Triangle,
+}
+
+impl Average {
+    //density is only used by stripe
+    pub fn from_name(name: &str, density: f64) -> Option<Self> {
+        match name {
+            "stripe" => Some(Average::Stripe(density)),
+            "triangle" => Some(Average::Triangle),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Average::Stripe(_) => "stripe",
+            Average::Triangle => "triangle",
+        }
+    }
+
+    //Value for one step of the orbit, z is the new point, power the magnitude of z^n before c
+    //was added and c the magnitude of c. None when the triangle is degenerate
+    #[inline(always)]
+    fn value(self, x: f64, y: f64, power: f64, c: f64) -> Option<f64> {
+        match self {
+            Average::Stripe(density) => Some(0.5 + 0.5 * (density * y.atan2(x)).sin()),
+            Average::Triangle => {
+                let low = (power - c).abs();
+                let high = power + c;
+                if high > low {
+                    Some(((x * x + y * y).sqrt() - low) / (high - low))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+//Running total of the values of an orbit, the last one is kept so it can be taken back out
+#[derive(Copy, Clone, Debug, Default)]
+pub(crate) struct Sum {
+    total: f64,
+    last: f64,
+    count: u32,
+}
+
+impl Sum {
+    #[inline(always)]
+    pub fn add(&mut self, average: Average, x: f64, y: f64, power: f64, c: f64) {
+        if let Some(value) = average.value(x, y, power, c) {
+            self.total += value;
+            self.last = value;
+            self.count += 1;
+        }
+    }
+
+    //Mean blended from the one before the last value, at a fraction of 0, to the full mean
+    pub fn mean(&self, fraction: f64) -> f64 {
+        match self.count {
+            0 => 0.0,
+            1 => self.total,
+            count => {
+                let after = self.total / count as f64;
+                let before = (self.total - self.last) / (count - 1) as f64;
+                before + (after - before) * fraction
+            }
+        }
+    }
+}
@@ -0,0 +1,289 @@
+//Orbit density rendering. Instead of colouring c by how its orbit behaves, c is sampled all over
+//the set and every point of the orbits that escape is counted on the pixel it lands on. Counting
+//orbits from three iteration windows into red, green and blue gives the Nebulabrot. Orbits are
+//of the plain z^2 + c map
+use crate::palette::Palette;
+use crate::{in_main_bulbs, Cancelled, Options, View, PROGRESS_INTERVAL};
+use image::{ImageBuffer, RgbImage};
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+
+//c is sampled from the square -RADIUS..RADIUS, everything outside it escapes straight away
+const RADIUS: f64 = 2.0;
+//Counts are scaled so this share of the non-zero pixels in a channel are below full brightness,
+//so a handful of very dense pixels don't leave the rest of the image dark
+const TONE_PERCENTILE: f64 = 0.999;
+//Gamma applied after scaling to bring out the faint orbits
+const TONE_GAMMA: f64 = 0.5;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Buddhabrot {
+    //Orbits escaping after min..=max iterations are counted towards red, green and blue
+    pub windows: [(u32, u32); 3],
+    //Pick c at random instead of from a regular grid
+    pub random: bool,
+}
+
+impl Buddhabrot {
+    //The same window in every channel
+    pub fn new(min: u32, max: u32, random: bool) -> Self {
+        Self {
+            windows: [(min, max); 3],
+            random,
+        }
+    }
+
+    //Classic Nebulabrot, red for the slowest orbits down to blue for the quickest. An orbit has
+    //to last 2 iterations to leave a point other than c, so every window goes up to at least
+    //that and low iteration counts don't leave a channel black
+    pub fn nebulabrot(max_iter: u32, random: bool) -> Self {
+        Self {
+            windows: [max_iter, max_iter / 10, max_iter / 100].map(|max| (1, max.max(2))),
+            random,
+        }
+    }
+
+    //Greyscale, coloured with the palette instead of by channel
+    pub fn single(&self) -> bool {
+        self.windows.iter().all(|&window| window == self.windows[0])
+    }
+
+    //Side of the grid of c values, width * height * samples^2 of them in total. Each row of the
+    //grid is one unit of work for the threads
+    pub fn rows(&self, options: &Options) -> usize {
+        ((options.width as f64 * options.height as f64).sqrt() * options.samples as f64) as usize
+    }
+
+    fn limit(&self) -> u32 {
+        self.windows.iter().map(|&(_, max)| max).max().unwrap_or(0)
+    }
+}
+
+impl fmt::Display for Buddhabrot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:?}{}",
+            if self.single() {
+                "Buddhabrot"
+            } else {
+                "Nebulabrot"
+            },
+            self.windows,
+            if self.random { " random" } else { "" }
+        )
+    }
+}
+
+//Orbit points counted per pixel, one count per channel
+pub struct Density {
+    pub width: u32,
+    pub height: u32,
+    pub counts: Vec<[u32; 3]>,
+}
+
+impl Density {
+    fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            counts: vec![[0; 3]; (width * height) as usize],
+        }
+    }
+
+    //Tone map each channel on its own and write it out, through the palette for a Buddhabrot
+    pub fn to_image(
+        &self,
+        buddhabrot: &Buddhabrot,
+        palette: &Palette,
+        max_colours: u32,
+    ) -> RgbImage {
+        let scales: Vec<f64> = (0..3)
+            .map(|channel| {
+                let mut counts: Vec<u32> = self
+                    .counts
+                    .iter()
+                    .map(|counts| counts[channel])
+                    .filter(|&count| count > 0)
+                    .collect();
+                counts.sort_unstable();
+                let index = ((counts.len().max(1) - 1) as f64 * TONE_PERCENTILE) as usize;
+                counts.get(index).copied().unwrap_or(1).max(1) as f64
+            })
+            .collect();
+        let tone =
+            |count: u32, channel: usize| (count as f64 / scales[channel]).min(1.0).powf(TONE_GAMMA);
+
+        let lut = palette.lut(max_colours);
+        ImageBuffer::from_fn(self.width, self.height, |x, y| {
+            let counts = self.counts[(y * self.width + x) as usize];
+            if buddhabrot.single() {
+                //Kept below 1 so the brightest pixels don't wrap back round to the start
+                image::Rgb(palette.lookup(&lut, tone(counts[0], 0).min(1.0 - f64::EPSILON)))
+            } else {
+                image::Rgb([0, 1, 2].map(|channel| (tone(counts[channel], channel) * 255.0) as u8))
+            }
+        })
+    }
+}
+
+//Render on options.threads threads, each counting into its own buffer that is added up at the
+//end. progress is called on the calling thread with the rows of c finished and the total
+pub fn render<P>(
+    options: &Options,
+    buddhabrot: &Buddhabrot,
+    mut progress: P,
+) -> Result<Density, Cancelled>
+where
+    P: FnMut(usize, usize),
+{
+    let rows = buddhabrot.rows(options);
+    let next = AtomicUsize::new(0);
+    let completed = AtomicUsize::new(0);
+
+    //Disconnects once every worker has returned and dropped its sender, see render_cpu
+    let (done, finished) = mpsc::channel::<()>();
+    let buffers = thread::scope(|scope| {
+        let workers: Vec<_> = (0..options.threads)
+            .map(|_| {
+                let done = done.clone();
+                scope.spawn(|| {
+                    let _done = done;
+                    worker(options, buddhabrot, rows, &next, &completed)
+                })
+            })
+            .collect();
+        drop(done);
+
+        progress(completed.load(Ordering::Acquire), rows);
+        while let Err(RecvTimeoutError::Timeout) = finished.recv_timeout(PROGRESS_INTERVAL) {
+            progress(completed.load(Ordering::Acquire), rows);
+        }
+
+        workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap())
+            .collect::<Result<Vec<_>, _>>()
+    })?;
+
+    let mut density = Density::new(options.width, options.height);
+    for buffer in buffers {
+        for (total, counts) in density.counts.iter_mut().zip(buffer.counts) {
+            for channel in 0..3 {
+                total[channel] += counts[channel];
+            }
+        }
+    }
+    Ok(density)
+}
+
+fn worker(
+    options: &Options,
+    buddhabrot: &Buddhabrot,
+    rows: usize,
+    next: &AtomicUsize,
+    completed: &AtomicUsize,
+) -> Result<Density, Cancelled> {
+    let mut density = Density::new(options.width, options.height);
+
+    let view = View::new(options);
+
+    let bailout2 = options.bailout * options.bailout;
+    let limit = buddhabrot.limit();
+    let spacing = 2.0 * RADIUS / rows as f64;
+
+    loop {
+        let row = next.fetch_add(1, Ordering::Relaxed);
+        if row >= rows {
+            return Ok(density);
+        }
+        if options.cancel.is_cancelled() {
+            return Err(Cancelled);
+        }
+
+        //Seeded by row so the image doesn't depend on which thread got which row
+        let mut rng = SplitMix64(row as u64);
+        for column in 0..rows {
+            let (cx, cy) = if buddhabrot.random {
+                (
+                    -RADIUS + rng.next_f64() * 2.0 * RADIUS,
+                    -RADIUS + rng.next_f64() * 2.0 * RADIUS,
+                )
+            } else {
+                (
+                    -RADIUS + (column as f64 + 0.5) * spacing,
+                    -RADIUS + (row as f64 + 0.5) * spacing,
+                )
+            };
+
+            //Never escapes so it would only be iterated for nothing
+            if in_main_bulbs(cx, cy) {
+                continue;
+            }
+
+            let iter = match escape_time(cx, cy, limit, bailout2) {
+                Some(iter) => iter,
+                None => continue,
+            };
+            let channels = buddhabrot
+                .windows
+                .map(|(min, max)| (min..=max).contains(&iter));
+            if !channels.contains(&true) {
+                continue;
+            }
+
+            //Go round again, counting every point of the orbit that lands in the view. c itself
+            //is left out, it would only add a flat disc of every sample
+            let mut x = cx;
+            let mut y = cy;
+            for _ in 1..iter {
+                let xtemp = x * x - y * y + cx;
+                y = 2.0 * x * y + cy;
+                x = xtemp;
+
+                let (px, py) = view.pixel(x, y);
+                if px >= 0.0 && py >= 0.0 && px < options.width as f64 && py < options.height as f64
+                {
+                    let counts =
+                        &mut density.counts[py as usize * options.width as usize + px as usize];
+                    for channel in 0..3 {
+                        counts[channel] += channels[channel] as u32;
+                    }
+                }
+            }
+        }
+        completed.fetch_add(1, Ordering::Release);
+    }
+}
+
+//Iterations before the orbit of c escapes, the same count the escape time kernels give, or None
+//if it hasn't after limit of them
+fn escape_time(cx: f64, cy: f64, limit: u32, bailout2: f64) -> Option<u32> {
+    let mut x = cx;
+    let mut y = cy;
+    for iter in 0..=limit {
+        if x * x + y * y >= bailout2 {
+            return Some(iter);
+        }
+        let xtemp = x * x - y * y + cx;
+        y = 2.0 * x * y + cy;
+        x = xtemp;
+    }
+    None
+}
+
+//SplitMix64, plenty for scattering samples
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_f64(&mut self) -> f64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
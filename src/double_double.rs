@@ -0,0 +1,236 @@
+//Double-double arithmetic, a value is stored as the unevaluated sum of two f64 which gives
+//about 106 bits of mantissa. Enough for zooms down to around 1e-30 while staying much
+//cheaper than arbitrary precision
+use crate::perturbation::BigFloat;
+use crate::Decimal;
+use std::convert::TryFrom;
+use std::ops::{Add, Mul, Neg, Sub};
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct DoubleDouble {
+    pub hi: f64,
+    pub lo: f64,
+}
+
+//Exact sum of two f64 as a rounded sum and the rounding error
+#[inline(always)]
+fn two_sum(a: f64, b: f64) -> (f64, f64) {
+    let s = a + b;
+    let v = s - a;
+    (s, (a - (s - v)) + (b - v))
+}
+
+//Same as two_sum but only valid when |a| >= |b|
+#[inline(always)]
+fn quick_two_sum(a: f64, b: f64) -> (f64, f64) {
+    let s = a + b;
+    (s, b - (s - a))
+}
+
+//Exact product of two f64 as a rounded product and the rounding error
+#[inline(always)]
+fn two_prod(a: f64, b: f64) -> (f64, f64) {
+    let p = a * b;
+    (p, a.mul_add(b, -p))
+}
+
+impl DoubleDouble {
+    pub fn new(hi: f64, lo: f64) -> Self {
+        let (hi, lo) = quick_two_sum(hi, lo);
+        Self { hi, lo }
+    }
+
+    pub fn from_decimal(value: &Decimal) -> Self {
+        let value: BigFloat = value.clone().with_base_and_precision::<2>(128).value();
+        let hi = value.to_f64().value();
+        let lo = (value - BigFloat::try_from(hi).unwrap()).to_f64().value();
+        Self::new(hi, lo)
+    }
+
+    #[inline(always)]
+    pub fn sqr(self) -> Self {
+        let (p, e) = two_prod(self.hi, self.hi);
+        let e = e + 2.0 * self.hi * self.lo;
+        let (hi, lo) = quick_two_sum(p, e);
+        Self { hi, lo }
+    }
+
+    #[inline(always)]
+    pub fn to_f64(self) -> f64 {
+        self.hi + self.lo
+    }
+}
+
+impl From<f64> for DoubleDouble {
+    fn from(value: f64) -> Self {
+        Self { hi: value, lo: 0.0 }
+    }
+}
+
+impl Add for DoubleDouble {
+    type Output = Self;
+
+    #[inline(always)]
+    fn add(self, other: Self) -> Self {
+        let (s, e) = two_sum(self.hi, other.hi);
+        let (t, f) = two_sum(self.lo, other.lo);
+        let (s, e) = quick_two_sum(s, e + t);
+        let (hi, lo) = quick_two_sum(s, e + f);
+        Self { hi, lo }
+    }
+}
+
+impl Add<f64> for DoubleDouble {
+    type Output = Self;
+
+    #[inline(always)]
+    fn add(self, other: f64) -> Self {
+        let (s, e) = two_sum(self.hi, other);
+        let (hi, lo) = quick_two_sum(s, e + self.lo);
+        Self { hi, lo }
+    }
+}
+
+impl Neg for DoubleDouble {
+    type Output = Self;
+
+    #[inline(always)]
+    fn neg(self) -> Self {
+        Self {
+            hi: -self.hi,
+            lo: -self.lo,
+        }
+    }
+}
+
+impl Sub for DoubleDouble {
+    type Output = Self;
+
+    #[inline(always)]
+    fn sub(self, other: Self) -> Self {
+        self + -other
+    }
+}
+
+impl Mul for DoubleDouble {
+    type Output = Self;
+
+    #[inline(always)]
+    fn mul(self, other: Self) -> Self {
+        let (p, e) = two_prod(self.hi, other.hi);
+        let e = e + (self.hi * other.lo + self.lo * other.hi);
+        let (hi, lo) = quick_two_sum(p, e);
+        Self { hi, lo }
+    }
+}
+
+impl Mul<f64> for DoubleDouble {
+    type Output = Self;
+
+    #[inline(always)]
+    fn mul(self, other: f64) -> Self {
+        let (p, e) = two_prod(self.hi, other);
+        let (hi, lo) = quick_two_sum(p, e + self.lo * other);
+        Self { hi, lo }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pow2(n: i32) -> f64 {
+        2f64.powi(n)
+    }
+
+    #[test]
+    fn two_sum_keeps_the_rounding_error() {
+        assert_eq!(two_sum(1.0, pow2(-60)), (1.0, pow2(-60)));
+        //1e16 + 1.5 rounds to the nearest even multiple of 2
+        assert_eq!(two_sum(1e16, 1.5), (1e16 + 2.0, -0.5));
+        assert_eq!(two_sum(-3.0, 3.0), (0.0, 0.0));
+    }
+
+    #[test]
+    fn two_prod_keeps_the_rounding_error() {
+        //(1 + 2^-30)^2 = 1 + 2^-29 + 2^-60, the last term is below an ulp of 1
+        let a = 1.0 + pow2(-30);
+        assert_eq!(two_prod(a, a), (1.0 + pow2(-29), pow2(-60)));
+        assert_eq!(two_prod(3.0, 0.5), (1.5, 0.0));
+    }
+
+    #[test]
+    fn new_normalises() {
+        assert_eq!(
+            DoubleDouble::new(1.0, 1.0),
+            DoubleDouble { hi: 2.0, lo: 0.0 }
+        );
+        assert_eq!(
+            DoubleDouble::new(1.0, pow2(-60)),
+            DoubleDouble {
+                hi: 1.0,
+                lo: pow2(-60)
+            }
+        );
+    }
+
+    #[test]
+    fn add_and_sub() {
+        let a = DoubleDouble::new(1.0, pow2(-60));
+        let b = DoubleDouble::new(pow2(-30), pow2(-90));
+        assert_eq!(
+            a + b,
+            DoubleDouble {
+                hi: 1.0 + pow2(-30),
+                lo: pow2(-60) + pow2(-90)
+            }
+        );
+        assert_eq!((a + b) - b, a);
+        assert_eq!(a + 1.0, DoubleDouble::new(2.0, pow2(-60)));
+    }
+
+    #[test]
+    fn mul_and_sqr() {
+        let a = DoubleDouble::from(1.0 + pow2(-30));
+        let expected = DoubleDouble {
+            hi: 1.0 + pow2(-29),
+            lo: pow2(-60),
+        };
+        assert_eq!(a * a, expected);
+        assert_eq!(a.sqr(), expected);
+        assert_eq!(a * (1.0 + pow2(-30)), expected);
+
+        //(1 + 2^-60)^2 = 1 + 2^-59 + 2^-120, the last term is past the end of lo
+        let b = DoubleDouble::new(1.0, pow2(-60));
+        assert_eq!(
+            b.sqr(),
+            DoubleDouble {
+                hi: 1.0,
+                lo: pow2(-59)
+            }
+        );
+        assert_eq!(b * b, b.sqr());
+    }
+
+    #[test]
+    fn cancellation_that_f64_loses() {
+        //In f64 the small term is gone as soon as it is added
+        let small = 1e-20;
+        assert_eq!((1.0 + small) - 1.0, 0.0);
+        let one = DoubleDouble::from(1.0);
+        assert_eq!(((one + small) - one).to_f64(), small);
+
+        //0.1 + 0.2 - 0.3 is about 5.6e-17 in f64, in double-double what is left is below the
+        //rounding of the decimals to 106 bits
+        assert!((0.1 + 0.2 - 0.3f64).abs() > 1e-17);
+        let decimal = |text: &str| DoubleDouble::from_decimal(&text.parse().unwrap());
+        let sum = decimal("0.1") + decimal("0.2") - decimal("0.3");
+        assert!(sum.to_f64().abs() < 1e-32);
+
+        //(1 + 2^-40)^2 - 1 needs the 2^-80 term that f64 rounds away
+        let a = 1.0 + pow2(-40);
+        assert_eq!(a * a - 1.0, pow2(-39));
+        let a = DoubleDouble::from(a);
+        assert_eq!((a.sqr() - one).to_f64(), pow2(-39) + pow2(-80));
+    }
+}
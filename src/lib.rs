@@ -1,8 +1,23 @@
+use average::{Average, Sum};
+use buddhabrot::Buddhabrot;
 use bytemuck::{Pod, Zeroable};
+use double_double::DoubleDouble;
+use image::{ImageBuffer, Luma, RgbImage};
+use lighting::Lighting;
+use newton::Newton;
 use ocl::ProQue;
+use palette::Palette;
+use perturbation::ReferenceOrbit;
+use simd::LANES;
 use std::fmt;
-use std::sync::mpsc::Sender;
-use std::sync::{Arc, Mutex};
+use std::ops::Neg;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tiles::{TileScheduler, TileWriter};
+use trap::{Closest, Trap};
 use vulkano::buffer::{Buffer, BufferCreateInfo, BufferUsage};
 use vulkano::command_buffer::allocator::StandardCommandBufferAllocator;
 use vulkano::command_buffer::allocator::StandardCommandBufferAllocatorCreateInfo;
@@ -21,37 +36,90 @@ use vulkano::pipeline::PipelineBindPoint;
 use vulkano::sync::GpuFuture;
 use vulkano::VulkanLibrary;
 
+pub mod average;
+pub mod buddhabrot;
+pub mod double_double;
+pub mod lighting;
+pub mod newton;
+pub mod palette;
+pub mod perturbation;
+pub mod progressive;
+pub mod simd;
+pub mod tiles;
+pub mod trap;
+
+//Arbitrary precision decimal used for the view so coordinates are kept exactly as given
+pub type Decimal = dashu_float::DBig;
+
 pub const DEFAULT_MAX_COLOURS: u32 = 256;
 pub const DEFAULT_WIDTH: u32 = 1024;
 pub const DEFAULT_HEIGHT: u32 = 1024;
 pub const DEFAULT_MAX_ITER: u32 = 256;
-pub const DEFAULT_CENTREX: f64 = -0.75;
-pub const DEFAULT_CENTREY: f64 = 0.0;
-pub const DEFAULT_SCALEY: f64 = 2.5;
+pub const DEFAULT_CENTREX: &str = "-0.75";
+pub const DEFAULT_CENTREY: &str = "0.0";
+pub const DEFAULT_SCALEY: &str = "2.5";
 pub const DEFAULT_SAMPLES: u32 = 1;
 pub const DEFAULT_THREADS: u32 = 1;
-pub const DEFAULT_COLOUR_CODE: u32 = 7;
 pub const DEFAULT_COLOURISE: bool = false;
 pub const DEFAULT_PROGRESS: bool = false;
 pub const DEFAULT_OCL: bool = false;
 pub const DEFAULT_VULKAN: bool = false;
 pub const DEFAULT_VULKAN_CHUNKS: u32 = 1;
 pub const DEFAULT_SERVICE: bool = false;
+pub const DEFAULT_SMOOTH: bool = false;
+pub const DEFAULT_BAILOUT: f64 = 2.0;
+pub const DEFAULT_HISTOGRAM: bool = false;
+pub const DEFAULT_DISTANCE: bool = false;
+pub const DEFAULT_INTERIOR_CHECK: bool = false;
+pub const DEFAULT_PERTURBATION: bool = false;
+pub const DEFAULT_DOUBLE_DOUBLE: bool = false;
+pub const DEFAULT_SIMD: bool = true;
+pub const DEFAULT_TILE_SIZE: u32 = 64;
+pub const DEFAULT_ADAPTIVE: bool = false;
+pub const DEFAULT_ADAPTIVE_THRESHOLD: f64 = 0.5;
+pub const DEFAULT_SUBDIVIDE: bool = false;
+pub const DEFAULT_PROGRESSIVE: bool = false;
+pub const DEFAULT_FRACTAL: Fractal = Fractal::Mandelbrot;
+pub const DEFAULT_EXPONENT: f64 = 2.0;
+pub const DEFAULT_FORMULA: Formula = Formula::Mandelbrot;
+pub const DEFAULT_ROTATION: f64 = 0.0;
+
+//Subsample spacing, relative to the size of the centre coordinates, below which f64 can no
+//longer tell neighbouring samples apart once errors build up over DEFAULT_MAX_ITER iterations
+const F64_LIMIT: f64 = f64::EPSILON * 256.0;
+//Same for double-double, about 106 bits
+const DOUBLE_DOUBLE_LIMIT: f64 = F64_LIMIT * f64::EPSILON * 0.5;
+
+//Orbits that come back this close to a saved point are treated as periodic
+const PERIODICITY_EPSILON: f64 = 1e-15;
+
+//How often render_cpu reports finished tiles
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(20);
+
+//Rows per opencl dispatch, cancelling is checked between them
+const OPENCL_CHUNK_ROWS: u32 = 64;
+
+//Rectangles this many pixels across or smaller are rendered instead of split any further
+const SUBDIVIDE_MIN: u32 = 8;
 
 //Struct for storing arguments
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug)]
 pub struct Options {
     pub max_colours: u32,
     pub max_iter: u32,
 
     pub width: u32,
     pub height: u32,
-    pub centrex: f64,
-    pub centrey: f64,
-    pub scaley: f64,
+    pub centrex: Decimal,
+    pub centrey: Decimal,
+    pub scaley: Decimal,
+    //Width of the view, None takes it from scaley and the aspect ratio
+    pub scalex: Option<Decimal>,
+    //Degrees the image is turned anticlockwise about the centre of the view
+    pub rotation: f64,
 
     pub samples: u32,
-    pub colour: u32,
+    pub palette: Palette,
     pub colourise: bool,
     pub threads: u32,
     pub thread_id: Option<u32>,
@@ -60,6 +128,210 @@ pub struct Options {
     pub vulkan: bool,
     pub vulkan_chunks: u32,
     pub service: bool,
+    //Use the normalized iteration count instead of the integer count when colouring
+    pub smooth: bool,
+    //Escape radius, larger values give a more accurate smooth gradient
+    pub bailout: f64,
+    //Spread the palette over the distribution of iteration counts instead of linearly
+    pub histogram: bool,
+    //Track the derivative and colour by the exterior distance estimate
+    pub distance: bool,
+    //Skip the main cardioid and period 2 bulb and stop orbits that turn out to be periodic
+    pub interior_check: bool,
+    //Render relative to a high precision reference orbit for zooms past what f64 can resolve
+    pub perturbation: bool,
+    //Force the double-double cpu kernel, it is picked automatically when f64 isn't enough
+    pub double_double: bool,
+    //Iterate several samples at once with vector instructions when the cpu supports them
+    pub simd: bool,
+    //Width and height in pixels of the tiles the cpu threads take turns claiming
+    pub tile_size: u32,
+    //Render at one sample per pixel first and only use the full samples on pixels that
+    //differ from a neighbour by more than adaptive_threshold smoothed iterations
+    pub adaptive: bool,
+    pub adaptive_threshold: f64,
+    //Skip the inside of rectangles whose border all has the same iteration count
+    pub subdivide: bool,
+    //Render coarse previews first, see progressive
+    pub progressive: bool,
+    //Checked by the kernels between tiles, rows and gpu dispatches
+    pub cancel: CancelToken,
+    pub fractal: Fractal,
+    //Power z is raised to each iteration, z^exponent + c. Whole powers are multiplied out and
+    //anything else, including negative powers, goes through polar form. Has to be above 1 or
+    //below -1, and negative powers don't escape in a way that can be smoothed or lit
+    pub exponent: f64,
+    //Abs or conjugate variant of the map, see Formula
+    pub formula: Formula,
+    //Render Newton's method on a polynomial instead, the escape time settings above don't apply
+    pub newton: Option<Newton>,
+    //Render orbit density instead, see buddhabrot
+    pub buddhabrot: Option<Buddhabrot>,
+    //Colour by how close orbits come to a shape or image instead of by when they escape
+    pub trap: Option<Trap>,
+    //Colour the outside by an average taken over the orbit instead, see average
+    pub average: Option<Average>,
+    //Light the outside as a 3D surface, see lighting
+    pub lighting: Option<Lighting>,
+}
+
+//Shared flag for stopping a render early, every clone refers to the same flag
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+//Result of a render that was stopped through its CancelToken, whatever it wrote is incomplete
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "render cancelled")
+    }
+}
+
+//Opencl renders can fail in opencl itself as well as be cancelled
+#[derive(Debug)]
+pub enum OpenClError {
+    Cancelled,
+    Ocl(ocl::Error),
+}
+
+impl From<ocl::Error> for OpenClError {
+    fn from(error: ocl::Error) -> Self {
+        OpenClError::Ocl(error)
+    }
+}
+
+impl fmt::Display for OpenClError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OpenClError::Cancelled => write!(f, "{}", Cancelled),
+            OpenClError::Ocl(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+//Vulkan renders can be cancelled or find the device can't run the shader
+#[derive(Debug)]
+pub enum VulkanError {
+    Cancelled,
+    Unsupported(String),
+}
+
+impl fmt::Display for VulkanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VulkanError::Cancelled => write!(f, "{}", Cancelled),
+            VulkanError::Unsupported(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+//What gets iterated. For the Mandelbrot set the pixel is c and z starts at 0, for a Julia set
+//c is fixed and the pixel is the starting z
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Fractal {
+    Mandelbrot,
+    Julia(f64, f64),
+}
+
+pub const FORMULAS: [&str; 6] = [
+    "mandelbrot",
+    "burning-ship",
+    "tricorn",
+    "celtic",
+    "perpendicular",
+    "buffalo",
+];
+
+//Variants of z^2 + c that take the abs or conjugate of parts of z. They all fold z before the
+//power except Celtic, which takes the abs of the real part of z^2 after it, and Buffalo does both
+//Order is shared with the opencl and vulkan kernels so keep it in sync with them
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Formula {
+    Mandelbrot,
+    BurningShip,
+    Tricorn,
+    Celtic,
+    Perpendicular,
+    Buffalo,
+}
+
+impl Formula {
+    pub fn from_name(name: &str) -> Option<Self> {
+        let index = FORMULAS.iter().position(|&formula| formula == name)?;
+        Some(
+            [
+                Formula::Mandelbrot,
+                Formula::BurningShip,
+                Formula::Tricorn,
+                Formula::Celtic,
+                Formula::Perpendicular,
+                Formula::Buffalo,
+            ][index],
+        )
+    }
+
+    pub fn name(self) -> &'static str {
+        FORMULAS[self as usize]
+    }
+
+    //What happens to the real and imaginary parts of z before the power
+    pub(crate) fn folds(self) -> (Fold, Fold) {
+        match self {
+            Formula::Mandelbrot | Formula::Celtic => (Fold::Keep, Fold::Keep),
+            Formula::BurningShip | Formula::Buffalo => (Fold::Abs, Fold::Abs),
+            Formula::Tricorn => (Fold::Keep, Fold::Negate),
+            Formula::Perpendicular => (Fold::Abs, Fold::Negate),
+        }
+    }
+
+    //Whether the real part of z^n is replaced by its abs after the power
+    pub(crate) fn abs_real(self) -> bool {
+        matches!(self, Formula::Celtic | Formula::Buffalo)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum Fold {
+    Keep,
+    Abs,
+    Negate,
+}
+
+impl Fold {
+    //Fold one part of z, negative is its sign bit. The same part of dz is flipped along with it
+    //so the derivative follows the folded orbit
+    #[inline(always)]
+    fn apply<T: Neg<Output = T>>(self, value: T, negative: bool, derivative: f64) -> (T, f64) {
+        match self {
+            Fold::Abs if negative => (-value, -derivative),
+            Fold::Negate => (-value, -derivative),
+            _ => (value, derivative),
+        }
+    }
+}
+
+//Arithmetic used by the cpu kernel
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Precision {
+    Double,
+    DoubleDouble,
+    Perturbation,
 }
 
 #[repr(C)]
@@ -71,13 +343,110 @@ pub struct VulkanOpts {
     pub iterations: u32,
     pub yoffset: u32,
     pub chunks: u32,
+    pub interior_check: u32,
+    pub distance: u32,
+    pub julia: u32,
+    pub formula: u32,
     pub scaley: f64,
     pub centrex: f64,
     pub centrey: f64,
+    pub bailout: f64,
+    pub julia_x: f64,
+    pub julia_y: f64,
+    pub exponent: f64,
+    pub scalex: f64,
+    pub rotation_cos: f64,
+    pub rotation_sin: f64,
 }
 
 impl Options {
+    //The view rounded to f64 for the kernels that don't need more precision
+    pub fn centrex_f64(&self) -> f64 {
+        self.centrex.to_f64().value()
+    }
+
+    pub fn centrey_f64(&self) -> f64 {
+        self.centrey.to_f64().value()
+    }
+
+    pub fn scaley_f64(&self) -> f64 {
+        self.scaley.to_f64().value()
+    }
+
+    pub fn scalex_f64(&self) -> f64 {
+        match &self.scalex {
+            Some(scalex) => scalex.to_f64().value(),
+            None => self.scaley_f64() * self.width as f64 / self.height as f64,
+        }
+    }
+
+    //Set the view from its corners, as they are before it is rotated, instead of the centre and
+    //scale. Row 0 of the image is at ymin. Everything is worked out at unlimited precision so
+    //no digits of the corners are lost
+    pub fn set_bounds(&mut self, xmin: &Decimal, xmax: &Decimal, ymin: &Decimal, ymax: &Decimal) {
+        let exact = |value: &Decimal| value.clone().with_precision(0).value();
+        let half = exact(&"0.5".parse().unwrap());
+        self.centrex = (exact(xmin) + exact(xmax)) * &half;
+        self.centrey = (exact(ymin) + exact(ymax)) * &half;
+        self.scalex = Some(exact(xmax) - exact(xmin));
+        self.scaley = exact(ymax) - exact(ymin);
+    }
+
+    //Distance between neighbouring subsamples, the smaller of the two directions
+    pub fn spacing(&self) -> f64 {
+        (self.scaley_f64() / self.height as f64).min(self.scalex_f64() / self.width as f64)
+            / self.samples as f64
+    }
+
+    //Fixed c when rendering a Julia set
+    pub fn julia(&self) -> Option<(f64, f64)> {
+        match self.fractal {
+            Fractal::Mandelbrot => None,
+            Fractal::Julia(re, im) => Some((re, im)),
+        }
+    }
+
+    //The plain z^2 + c Mandelbrot set, the only one the main bulbs check and perturbation handle
+    pub fn classic(&self) -> bool {
+        self.julia().is_none() && self.exponent == 2.0 && self.formula == Formula::Mandelbrot
+    }
+
+    //Pick the cheapest kernel that can still resolve the view unless one is forced. The
+    //perturbation kernel only does the classic set so anything else stops at double-double, and
+    //only the f64 kernel has exponents other than 2 or Newton's method
+    pub fn precision(&self) -> Precision {
+        if self.exponent != 2.0 || self.newton.is_some() {
+            return Precision::Double;
+        }
+        if self.perturbation && self.classic() {
+            return Precision::Perturbation;
+        }
+        if self.double_double {
+            return Precision::DoubleDouble;
+        }
+
+        let spacing = self.spacing();
+        let magnitude = self
+            .centrex_f64()
+            .abs()
+            .max(self.centrey_f64().abs())
+            .max(1.0);
+        //Rounding errors pile up along the orbit, and longer orbits diverged well before the
+        //spacing reached the limits, so grow them with the cube of the extra iterations
+        let growth = (self.max_iter as f64 / DEFAULT_MAX_ITER as f64)
+            .max(1.0)
+            .powi(3);
+        if spacing >= magnitude * F64_LIMIT * growth {
+            Precision::Double
+        } else if spacing >= magnitude * DOUBLE_DOUBLE_LIMIT * growth || !self.classic() {
+            Precision::DoubleDouble
+        } else {
+            Precision::Perturbation
+        }
+    }
+
     pub fn as_vulkan_opts(&self) -> VulkanOpts {
+        let view = View::new(self);
         VulkanOpts {
             width: self.width,
             height: self.height,
@@ -85,9 +454,20 @@ impl Options {
             iterations: self.max_iter,
             yoffset: 0,
             chunks: self.vulkan_chunks,
-            scaley: self.scaley,
-            centrex: self.centrex,
-            centrey: self.centrey,
+            interior_check: self.interior_check as u32,
+            distance: self.distance as u32,
+            julia: self.julia().is_some() as u32,
+            formula: self.formula as u32,
+            scaley: self.scaley_f64(),
+            centrex: self.centrex_f64(),
+            centrey: self.centrey_f64(),
+            bailout: self.bailout,
+            julia_x: self.julia().unwrap_or_default().0,
+            julia_y: self.julia().unwrap_or_default().1,
+            exponent: self.exponent,
+            scalex: self.scalex_f64(),
+            rotation_cos: view.cos,
+            rotation_sin: view.sin,
         }
     }
 }
@@ -99,11 +479,13 @@ impl Default for Options {
             max_iter: DEFAULT_MAX_ITER,
             width: DEFAULT_WIDTH,
             height: DEFAULT_HEIGHT,
-            centrex: DEFAULT_CENTREX,
-            centrey: DEFAULT_CENTREY,
-            scaley: DEFAULT_SCALEY,
+            centrex: DEFAULT_CENTREX.parse().unwrap(),
+            centrey: DEFAULT_CENTREY.parse().unwrap(),
+            scaley: DEFAULT_SCALEY.parse().unwrap(),
+            scalex: None,
+            rotation: DEFAULT_ROTATION,
             samples: DEFAULT_SAMPLES,
-            colour: DEFAULT_COLOUR_CODE,
+            palette: Palette::default(),
             colourise: DEFAULT_COLOURISE,
             threads: DEFAULT_THREADS,
             progress: DEFAULT_PROGRESS,
@@ -111,6 +493,28 @@ impl Default for Options {
             vulkan: DEFAULT_VULKAN,
             vulkan_chunks: DEFAULT_VULKAN_CHUNKS,
             service: DEFAULT_SERVICE,
+            smooth: DEFAULT_SMOOTH,
+            bailout: DEFAULT_BAILOUT,
+            histogram: DEFAULT_HISTOGRAM,
+            distance: DEFAULT_DISTANCE,
+            interior_check: DEFAULT_INTERIOR_CHECK,
+            perturbation: DEFAULT_PERTURBATION,
+            double_double: DEFAULT_DOUBLE_DOUBLE,
+            simd: DEFAULT_SIMD,
+            tile_size: DEFAULT_TILE_SIZE,
+            adaptive: DEFAULT_ADAPTIVE,
+            adaptive_threshold: DEFAULT_ADAPTIVE_THRESHOLD,
+            subdivide: DEFAULT_SUBDIVIDE,
+            progressive: DEFAULT_PROGRESSIVE,
+            cancel: CancelToken::new(),
+            fractal: DEFAULT_FRACTAL,
+            exponent: DEFAULT_EXPONENT,
+            formula: DEFAULT_FORMULA,
+            newton: None,
+            buddhabrot: None,
+            trap: None,
+            average: None,
+            lighting: None,
             thread_id: None,
         }
     }
@@ -120,117 +524,1077 @@ impl fmt::Display for Options {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "Position ({}, {}) with scale {} and {} iterations at size {}x{} {} samples per pixel {} threads and colour code {}",
+            "{} at ({}, {}) with scale {} rotated {} and {} iterations at size {}x{} {} samples per pixel {} threads and palette {} (smooth {}, bailout {}, histogram {}, distance {}, interior check {}, precision {:?}, simd {}, adaptive {}, subdivide {}, exponent {}, formula {}, trap {}, average {}, lighting {})",
+            match (&self.buddhabrot, &self.newton) {
+                (Some(buddhabrot), _) => buddhabrot.to_string(),
+                (None, Some(newton)) => newton.to_string(),
+                (None, None) => format!("{:?}", self.fractal),
+            },
             self.centrex,
             self.centrey,
-            self.scaley,
+            match &self.scalex {
+                Some(scalex) => format!("{} by {}", scalex, self.scaley),
+                None => self.scaley.to_string(),
+            },
+            self.rotation,
             self.max_iter,
             self.width,
             self.height,
             self.samples * self.samples,
             self.threads,
-            self.colour
+            self.palette.name,
+            self.smooth,
+            self.bailout,
+            self.histogram,
+            self.distance,
+            self.interior_check,
+            self.precision(),
+            self.simd,
+            self.adaptive,
+            self.subdivide,
+            self.exponent,
+            self.formula.name(),
+            self.trap
+                .as_ref()
+                .map_or(String::from("none"), Trap::to_string),
+            match self.average {
+                Some(Average::Stripe(density)) => format!("stripe density {}", density),
+                Some(average) => average.name().to_string(),
+                None => String::from("none"),
+            },
+            self.lighting.is_some()
         )
     }
 }
 
+//Raw escape-time result for a single pixel, averaged over its samples
+//Layout is shared with the opencl and vulkan kernels so keep it in sync with them
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Pod, Zeroable)]
+pub struct EscapeData {
+    pub iter: u32,
+    pub inside: u32,
+    pub mag: f64,
+    //Normalized (continuous) iteration count, averaged the same way as iter
+    pub smooth: f64,
+    //Exterior distance estimate in the same units as the view coordinates, 0 inside the set
+    pub distance: f64,
+    //1 + index of the root a Newton orbit converged to, 0 for none and escape time fractals
+    pub root: u32,
+    pub _padding: u32,
+    //Closest the orbits came to the trap and where, relative to its centre. Averaged over
+    //every sample, escaped or not, and 0 without a trap
+    pub trap: f64,
+    pub trap_x: f64,
+    pub trap_y: f64,
+    //Average colouring value in 0..1 blended by the smooth iteration count, averaged like mag
+    pub average: f64,
+    //Direction of z / dz, the way the distance estimate grows, averaged and made unit length.
+    //Only filled in by the cpu kernels with distance on, 0 otherwise
+    pub normal_x: f64,
+    pub normal_y: f64,
+}
+
+unsafe impl ocl::OclPrm for EscapeData {}
+
+//Per-pixel escape-time data for a whole render, kept separate from colouring so
+//a render can be recoloured or analysed without computing it again
+#[derive(Clone, Debug)]
+pub struct IterationField {
+    pub width: u32,
+    pub height: u32,
+    pub max_iter: u32,
+    //Size of a pixel in view coordinates, used to scale distance estimates. The geometric mean
+    //of its width and height, which only differ when the bounds aren't the shape of the image
+    pub pixel_size: f64,
+    pub data: Vec<EscapeData>,
+    //Id of the thread that computed each pixel, only filled in by the cpu backend
+    pub owner: Vec<u32>,
+}
+
+impl IterationField {
+    pub fn new(options: &Options) -> Self {
+        let size = (options.width * options.height) as usize;
+        Self {
+            width: options.width,
+            height: options.height,
+            max_iter: options.max_iter,
+            pixel_size: (options.scalex_f64() / options.width as f64
+                * (options.scaley_f64() / options.height as f64))
+                .sqrt(),
+            data: vec![EscapeData::default(); size],
+            owner: vec![0; size],
+        }
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> &EscapeData {
+        &self.data[(y * self.width + x) as usize]
+    }
+
+    //Distance estimate of every pixel measured in pixels, for use in shading
+    pub fn distance_field(&self) -> Vec<f64> {
+        self.data
+            .iter()
+            .map(|data| data.distance / self.pixel_size)
+            .collect()
+    }
+
+    //Pixels on an edge, where a neighbour differs by more than adaptive_threshold smoothed
+    //iterations or only one of the two is inside the set. Traps and averages change colour within
+    //a single iteration, so they are compared by how far apart they are on the palette, where the
+    //threshold covers the same distance it does in iterations, and texture traps by their texel.
+    //Both pixels of such a pair are marked
+    pub fn edge_mask(&self, options: &Options) -> Vec<bool> {
+        let threshold = options.adaptive_threshold;
+        let palette_threshold = threshold / self.max_iter as f64;
+        let trap_differs = |a: &EscapeData, b: &EscapeData| match &options.trap {
+            Some(trap) => match (
+                trap.texel(a.trap_x, a.trap_y),
+                trap.texel(b.trap_x, b.trap_y),
+            ) {
+                (None, None) => {
+                    (trap_position(a.trap) - trap_position(b.trap)).abs() > palette_threshold
+                }
+                (a, b) => a != b,
+            },
+            None => false,
+        };
+        let differs = |a: &EscapeData, b: &EscapeData| {
+            a.inside != b.inside
+                || a.root != b.root
+                || trap_differs(a, b)
+                || (a.inside == 0
+                    && options.average.is_some()
+                    && (a.average - b.average).abs() > palette_threshold)
+                || (a.inside == 0 && (a.smooth - b.smooth).abs() > threshold)
+        };
+        let width = self.width as usize;
+        let mut mask = vec![false; self.data.len()];
+
+        for i in 0..self.data.len() {
+            if (i + 1) % width != 0 && differs(&self.data[i], &self.data[i + 1]) {
+                mask[i] = true;
+                mask[i + 1] = true;
+            }
+            if i + width < self.data.len() && differs(&self.data[i], &self.data[i + width]) {
+                mask[i] = true;
+                mask[i + width] = true;
+            }
+        }
+
+        mask
+    }
+
+    //Distance field as a 16 bit greyscale image, distances of max_distance pixels or more are white
+    pub fn distance_image(&self, max_distance: f64) -> ImageBuffer<Luma<u16>, Vec<u16>> {
+        let distances = self.distance_field();
+        ImageBuffer::from_fn(self.width, self.height, |x, y| {
+            let distance = distances[(y * self.width + x) as usize];
+            Luma([((distance / max_distance).clamp(0.0, 1.0) * u16::MAX as f64) as u16])
+        })
+    }
+}
+
+//Palette position of how close an orbit came to a trap. The square root spreads out the small
+//distances where the detail is, kept below 1 so it doesn't wrap back round
+fn trap_position(distance: f64) -> f64 {
+    distance.sqrt().tanh().min(1.0 - f64::EPSILON)
+}
+
+//Normalized iteration count of an escaped orbit, the log-log term removes the banding
+//that comes from only knowing which iteration the orbit crossed the bailout radius on. The
+//magnitude grows by a power of the exponent each iteration so that is the base of the outer log
 #[inline(always)]
-fn iterations2colour(options: &Options, iter: u32, max_iter: u32, flags: u32) -> u32 {
-    let iter = (iter * options.max_colours / max_iter) & (options.max_colours - 1);
-    (((flags & 4) << 14) | ((flags & 2) << 7) | (flags & 1)) * iter
+pub fn smooth_iter(iter: u32, mag: f64, bailout: f64, exponent: f64) -> f64 {
+    iter as f64 + 1.0 - (mag.ln() / bailout.ln()).log2() / exponent.abs().log2()
 }
 
-fn interlocked_increment(shared: Arc<Mutex<u32>>) -> u32 {
-    let mut current = shared.lock().unwrap();
-    let temp = *current;
-    *current += 1;
-    temp
+//Cumulative distribution of the iteration counts of escaped pixels, entry i is the share of
+//pixels that escaped in fewer than i iterations
+fn iteration_cdf(options: &Options, field: &IterationField) -> Vec<f64> {
+    let bins = field.max_iter as usize + 2;
+    let mut counts = vec![0u64; bins];
+    let mut total = 0;
+
+    for data in field.data.iter().filter(|data| data.inside == 0) {
+        let value = if options.smooth {
+            data.smooth.max(0.0) as usize
+        } else {
+            data.iter as usize
+        };
+        counts[value.min(bins - 1)] += 1;
+        total += 1;
+    }
+
+    let mut cdf = Vec::with_capacity(bins + 1);
+    let mut sum = 0;
+    cdf.push(0.0);
+    for count in counts {
+        sum += count;
+        cdf.push(sum as f64 / total.max(1) as f64);
+    }
+    cdf
 }
 
-pub fn mandelbrot(options: Options, sender: Sender<(u32, u32)>, current_line: Arc<Mutex<u32>>) {
-    let scalex: f64 = options.scaley * options.width as f64 / options.height as f64;
-    let colour = if options.colourise {
-        options.thread_id.unwrap() % 7 + 1
+//Turn an iteration field into packed 0x00bbggrr colours
+pub fn colour_field(options: &Options, field: &IterationField) -> Vec<u32> {
+    let lut = options.palette.lut(options.max_colours);
+    let light = options
+        .lighting
+        .map(|lighting| lighting.light(options, field));
+    let cdf = if options.histogram {
+        iteration_cdf(options, field)
     } else {
-        options.colour
+        Vec::new()
     };
 
-    let dx: f64 = scalex / options.width as f64 / options.samples as f64;
-    let dy: f64 = options.scaley / options.height as f64 / options.samples as f64;
+    //Colourise gives each thread its own palette but keeps the user's offset, repeat and reverse
+    let thread_luts: Vec<Vec<[u8; 3]>> = if options.colourise {
+        palette::BUILTIN_PALETTES[..7]
+            .iter()
+            .map(|name| {
+                let stops = Palette::builtin(name).unwrap().stops;
+                Palette {
+                    stops,
+                    ..options.palette.clone()
+                }
+                .lut(options.max_colours)
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    field
+        .data
+        .iter()
+        .enumerate()
+        .map(|(i, data)| {
+            let lut = if options.colourise {
+                &thread_luts[(field.owner[i] % 7) as usize]
+            } else {
+                &lut
+            };
+
+            //Orbits that hit a texture take its colour, everything else goes by how close it
+            //came
+            if let Some(trap) = &options.trap {
+                let [r, g, b] = trap
+                    .texel(data.trap_x, data.trap_y)
+                    .unwrap_or_else(|| options.palette.lookup(lut, trap_position(data.trap)));
+                return [r, g, b];
+            }
+
+            if data.inside != 0 {
+                return [0, 0, 0];
+            }
 
-    let startx = options.centrex - scalex * 0.5;
-    let starty = options.centrey - options.scaley * 0.5;
-    let mut iy = interlocked_increment(current_line.clone());
+            //Each root gets its own part of the palette, Nova orbits that settle somewhere
+            //else fall through to the usual colouring
+            if let (Some(newton), true) = (&options.newton, data.root > 0) {
+                let t = ((data.root - 1) as f64 + 0.5) / newton.degree() as f64;
+                return newton::shade(options.palette.lookup(lut, t), data.smooth);
+            }
 
-    while iy < options.height {
-        for ix in 0..options.width {
-            let mut totaliter: u32 = 0;
+            let t = if options.average.is_some() {
+                //Already in 0..1, kept below 1 so it doesn't wrap back round either
+                data.average.min(1.0 - f64::EPSILON)
+            } else if options.distance {
+                //Boundary filaments are dark and fade out over a few pixels, kept below 1 so
+                //distant pixels don't wrap back round to the start of the palette
+                (data.distance / field.pixel_size)
+                    .tanh()
+                    .min(1.0 - f64::EPSILON)
+            } else {
+                let value = if options.smooth {
+                    data.smooth
+                } else {
+                    data.iter as f64
+                };
+
+                if options.histogram {
+                    //Interpolate within the bin so smooth values stay band free
+                    let bin = (value.max(0.0) as usize).min(cdf.len() - 2);
+                    let frac = (value - bin as f64).clamp(0.0, 1.0);
+                    cdf[bin] + (cdf[bin + 1] - cdf[bin]) * frac
+                } else {
+                    value / field.max_iter as f64
+                }
+            };
+
+            options.palette.lookup(lut, t)
+        })
+        .enumerate()
+        .map(|(i, colour)| {
+            let [r, g, b] = match &light {
+                Some(light) => lighting::shade(colour, light[i]),
+                None => colour,
+            };
+            ((b as u32) << 16) | ((g as u32) << 8) | r as u32
+        })
+        .collect()
+}
+
+//Colour an iteration field and write it into an image
+pub fn field_to_image(options: &Options, field: &IterationField) -> RgbImage {
+    let colours = colour_field(options, field);
+    let mut img: RgbImage = ImageBuffer::new(field.width, field.height);
+
+    for (x, y, pixel) in img.enumerate_pixels_mut() {
+        //32 bit number but only storing rgb so split it into its 3 8 bit components
+        let colour = colours[(y * field.width + x) as usize];
+        let b = ((colour & 0x00ff0000) >> 16) as u8;
+        let g = ((colour & 0x0000ff00) >> 8) as u8;
+        let r = (colour & 0x000000ff) as u8;
+        *pixel = image::Rgb([r, g, b]);
+    }
+
+    img
+}
+
+//Where the subsamples of the view are. Subsamples are counted from the top left corner and
+//the grid is turned by the rotation about the centre of the view
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct View {
+    startx: f64,
+    starty: f64,
+    //Size of a subsample
+    dx: f64,
+    dy: f64,
+    halfx: f64,
+    halfy: f64,
+    cos: f64,
+    sin: f64,
+    samples: f64,
+}
+
+impl View {
+    pub fn new(options: &Options) -> Self {
+        let scalex = options.scalex_f64();
+        let scaley = options.scaley_f64();
+        let (sin, cos) = options.rotation.to_radians().sin_cos();
+        let halfx = scalex * 0.5;
+        let halfy = scaley * 0.5;
+        Self {
+            startx: options.centrex_f64() - (halfx * cos - halfy * sin),
+            starty: options.centrey_f64() - (halfx * sin + halfy * cos),
+            dx: scalex / options.width as f64 / options.samples as f64,
+            dy: scaley / options.height as f64 / options.samples as f64,
+            halfx,
+            halfy,
+            cos,
+            sin,
+            samples: options.samples as f64,
+        }
+    }
+
+    #[inline(always)]
+    fn rotate(&self, x: f64, y: f64) -> (f64, f64) {
+        (x * self.cos - y * self.sin, x * self.sin + y * self.cos)
+    }
+
+    //Position of a subsample
+    #[inline(always)]
+    pub fn point(&self, sx: f64, sy: f64) -> (f64, f64) {
+        let (x, y) = self.rotate(sx * self.dx, sy * self.dy);
+        (self.startx + x, self.starty + y)
+    }
+
+    //Position of a subsample relative to the centre, for kernels that keep the centre at a
+    //higher precision
+    #[inline(always)]
+    pub fn offset(&self, sx: f64, sy: f64) -> (f64, f64) {
+        self.rotate(sx * self.dx - self.halfx, sy * self.dy - self.halfy)
+    }
+
+    //Inverse of point, in pixels rather than subsamples
+    #[inline(always)]
+    pub fn pixel(&self, x: f64, y: f64) -> (f64, f64) {
+        let (x, y) = (x - self.startx, y - self.starty);
+        let (u, v) = (x * self.cos + y * self.sin, y * self.cos - x * self.sin);
+        (u / self.dx / self.samples, v / self.dy / self.samples)
+    }
+}
+
+//State of an orbit once it escaped or ran out of iterations
+#[derive(Copy, Clone, Default)]
+pub(crate) struct Orbit {
+    pub iter: u32,
+    pub x: f64,
+    pub y: f64,
+    //Derivative dz/dc, only tracked when estimating distance. Newton orbits keep their last
+    //step here instead
+    pub dx: f64,
+    pub dy: f64,
+    //Root a Newton orbit converged to, see newton
+    pub root: u32,
+    //Only tracked with a trap
+    pub trap: Closest,
+    //Only tracked with average colouring
+    pub average: Sum,
+}
 
+//Analytic test for points inside the main cardioid or the period 2 bulb
+#[inline(always)]
+fn in_main_bulbs(x: f64, y: f64) -> bool {
+    let xq = x - 0.25;
+    let q = xq * xq + y * y;
+    q * (q + xq) <= 0.25 * y * y || (x + 1.0) * (x + 1.0) + y * y <= 0.0625
+}
+
+#[inline(always)]
+fn iterate(options: &Options, x0: f64, y0: f64, bailout2: f64) -> Orbit {
+    let mut iter: u32 = 0;
+    let mut x: f64 = x0;
+    let mut y: f64 = y0;
+    let mut dx: f64 = 1.0;
+    let mut dy: f64 = 0.0;
+
+    //A Julia set's derivative is taken with respect to the starting z so it has no + 1 term
+    let (cx, cy, dc) = match options.fractal {
+        Fractal::Mandelbrot => (x0, y0, 1.0),
+        Fractal::Julia(cx, cy) => (cx, cy, 0.0),
+    };
+
+    let quadratic = options.exponent == 2.0;
+    let (fold_x, fold_y) = options.formula.folds();
+    let abs_real = options.formula.abs_real();
+    let mut closest = Closest::default();
+    let mut sum = Sum::default();
+    let cmag = (cx * cx + cy * cy).sqrt();
+    //Traps colour the inside too so those orbits still have to be run
+    if options.interior_check
+        && options.classic()
+        && options.trap.is_none()
+        && in_main_bulbs(x0, y0)
+    {
+        iter = options.max_iter + 1;
+    }
+
+    //Brent style periodicity check, the saved point moves on each time the window doubles
+    let mut checkx = x;
+    let mut checky = y;
+    let mut period: u32 = 0;
+    let mut check_limit: u32 = 1;
+
+    while x * x + y * y < bailout2 && iter <= options.max_iter {
+        //|z^n| before c is added, only the triangle average needs it
+        let power = match options.average {
+            Some(Average::Triangle) if quadratic => x * x + y * y,
+            Some(Average::Triangle) => (x * x + y * y).powf(options.exponent * 0.5),
+            _ => 0.0,
+        };
+        let (zx, zdx) = fold_x.apply(x, x.is_sign_negative(), dx);
+        let (zy, zdy) = fold_y.apply(y, y.is_sign_negative(), dy);
+
+        //z^n is taken as z^(n - 1) * z so the derivative can reuse z^(n - 1)
+        let (px, py) = if quadratic {
+            (zx, zy)
+        } else {
+            complex_pow(zx, zy, options.exponent - 1.0)
+        };
+        //Celtic and Buffalo take the abs of the real part, flipping the same part of dz with it
+        let nx = px * zx - py * zy;
+        let flip = abs_real && nx.is_sign_negative();
+
+        if options.distance {
+            //dz = n * z^(n - 1) * dz + 1
+            let ndx = options.exponent * (px * zdx - py * zdy);
+            dx = if flip { -ndx } else { ndx } + dc;
+            dy = options.exponent * (px * zdy + py * zdx);
+        }
+
+        x = if flip { -nx } else { nx } + cx;
+        y = px * zy + py * zx + cy;
+        iter += 1;
+        if let Some(trap) = &options.trap {
+            closest.update(trap, x, y);
+        }
+        if let Some(average) = options.average {
+            sum.add(average, x, y, power, cmag);
+        }
+
+        if options.interior_check {
+            if (x - checkx).abs() < PERIODICITY_EPSILON && (y - checky).abs() < PERIODICITY_EPSILON
+            {
+                iter = options.max_iter + 1;
+                break;
+            }
+
+            period += 1;
+            if period == check_limit {
+                period = 0;
+                check_limit = check_limit.saturating_mul(2);
+                checkx = x;
+                checky = y;
+            }
+        }
+    }
+
+    Orbit {
+        iter,
+        x,
+        y,
+        dx,
+        dy,
+        root: 0,
+        trap: closest,
+        average: sum,
+    }
+}
+
+//z^n, whole powers are multiplied out by repeated squaring and anything else goes through
+//polar form
+#[inline(always)]
+fn complex_pow(x: f64, y: f64, n: f64) -> (f64, f64) {
+    if n >= 0.0 && n.fract() == 0.0 && n <= u32::MAX as f64 {
+        let (mut px, mut py) = (1.0, 0.0);
+        let (mut zx, mut zy) = (x, y);
+        let mut e = n as u32;
+        while e > 0 {
+            if e & 1 == 1 {
+                (px, py) = (px * zx - py * zy, px * zy + py * zx);
+            }
+            (zx, zy) = (zx * zx - zy * zy, 2.0 * zx * zy);
+            e >>= 1;
+        }
+        (px, py)
+    } else {
+        let r = (x * x + y * y).powf(n * 0.5);
+        let theta = y.atan2(x) * n;
+        (r * theta.cos(), r * theta.sin())
+    }
+}
+
+//Double-double version of iterate, the periodicity check is skipped as its tolerance is far
+//bigger than the pixels at the zooms this kernel is used for
+#[inline(always)]
+fn iterate_dd(options: &Options, x0: DoubleDouble, y0: DoubleDouble, bailout2: f64) -> Orbit {
+    let mut iter: u32 = 0;
+    let mut x = x0;
+    let mut y = y0;
+    let mut dx: f64 = 1.0;
+    let mut dy: f64 = 0.0;
+
+    let (cx, cy, dc) = match options.fractal {
+        Fractal::Mandelbrot => (x0, y0, 1.0),
+        Fractal::Julia(cx, cy) => (cx.into(), cy.into(), 0.0),
+    };
+
+    let (fold_x, fold_y) = options.formula.folds();
+    let abs_real = options.formula.abs_real();
+    let mut closest = Closest::default();
+    let mut sum = Sum::default();
+    let cmag = (cx.hi * cx.hi + cy.hi * cy.hi).sqrt();
+    if options.interior_check
+        && options.classic()
+        && options.trap.is_none()
+        && in_main_bulbs(x0.hi, y0.hi)
+    {
+        iter = options.max_iter + 1;
+    }
+
+    while x.hi * x.hi + y.hi * y.hi < bailout2 && iter <= options.max_iter {
+        let power = x.hi * x.hi + y.hi * y.hi;
+        let (zx, zdx) = fold_x.apply(x, x.hi.is_sign_negative(), dx);
+        let (zy, zdy) = fold_y.apply(y, y.hi.is_sign_negative(), dy);
+
+        let nx = zx.sqr() - zy.sqr();
+        let flip = abs_real && nx.hi.is_sign_negative();
+
+        if options.distance {
+            //The derivative doesn't need the extra precision
+            let ndx = 2.0 * (zx.hi * zdx - zy.hi * zdy);
+            dx = if flip { -ndx } else { ndx } + dc;
+            dy = 2.0 * (zx.hi * zdy + zy.hi * zdx);
+        }
+
+        let xy = zx * zy;
+        x = if flip { -nx } else { nx } + cx;
+        y = xy + xy + cy;
+        iter += 1;
+        if let Some(trap) = &options.trap {
+            closest.update(trap, x.hi, y.hi);
+        }
+        if let Some(average) = options.average {
+            sum.add(average, x.hi, y.hi, power, cmag);
+        }
+    }
+
+    Orbit {
+        iter,
+        x: x.to_f64(),
+        y: y.to_f64(),
+        dx,
+        dy,
+        root: 0,
+        trap: closest,
+        average: sum,
+    }
+}
+
+//Run the cpu kernels over every tile in the scheduler on options.threads threads, using the
+//perturbation kernel when given a reference orbit. progress is called on the calling thread
+//with the number of finished tiles and the total until they are all done or cancelled
+pub fn render_cpu<P>(
+    options: &Options,
+    tiles: &TileScheduler,
+    reference: Option<&ReferenceOrbit>,
+    mut progress: P,
+) -> Result<(), Cancelled>
+where
+    P: FnMut(usize, usize),
+{
+    //Every worker holds a sender that is dropped when it returns, so the channel disconnects as
+    //soon as the last one is done, whether it finished or was cancelled
+    let (done, finished) = mpsc::channel::<()>();
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..options.threads)
+            .map(|i| {
+                let mut local_options = options.clone();
+                local_options.thread_id = Some(i);
+                let done = done.clone();
+                scope.spawn(move || {
+                    let _done = done;
+                    match reference {
+                        Some(reference) => {
+                            perturbation::mandelbrot(&local_options, reference, tiles)
+                        }
+                        None => mandelbrot(&local_options, tiles),
+                    }
+                })
+            })
+            .collect();
+        drop(done);
+
+        //Workers write straight into the field so only the finished tiles need following
+        progress(tiles.completed(), tiles.len());
+        while let Err(RecvTimeoutError::Timeout) = finished.recv_timeout(PROGRESS_INTERVAL) {
+            progress(tiles.completed(), tiles.len());
+        }
+
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().unwrap())
+    })
+}
+
+pub fn mandelbrot(options: &Options, tiles: &TileScheduler) -> Result<(), Cancelled> {
+    if let Some(newton) = &options.newton {
+        return newton::mandelbrot(options, newton, tiles);
+    }
+
+    let view = View::new(options);
+    let bailout2 = options.bailout * options.bailout;
+
+    if options.precision() == Precision::DoubleDouble {
+        //Only the centre needs the extra precision, offsets from it are fine in f64
+        let centrex = DoubleDouble::from_decimal(&options.centrex);
+        let centrey = DoubleDouble::from_decimal(&options.centrey);
+        render_tiles(options, tiles, |sx, sy| {
+            let (x, y) = view.offset(sx, sy);
+            iterate_dd(options, centrex + x, centrey + y, bailout2)
+        })
+    } else if options.simd {
+        render_tiles_batched(options, tiles, |sx, sy, orbits| {
+            for ((sx, sy), orbits) in sx
+                .chunks(LANES)
+                .zip(sy.chunks(LANES))
+                .zip(orbits.chunks_mut(LANES))
+            {
+                //The last chunk of a row is padded by repeating its first sample
+                let (x, y) = view.point(sx[0], sy[0]);
+                let mut x0 = [x; LANES];
+                let mut y0 = [y; LANES];
+                for lane in 0..sx.len() {
+                    (x0[lane], y0[lane]) = view.point(sx[lane], sy[lane]);
+                }
+
+                let lanes = simd::iterate_lanes(options, x0, y0, bailout2);
+                orbits.copy_from_slice(&lanes[..orbits.len()]);
+            }
+        })
+    } else {
+        render_tiles(options, tiles, |sx, sy| {
+            let (x, y) = view.point(sx, sy);
+            iterate(options, x, y, bailout2)
+        })
+    }
+}
+
+//Tile loop shared by the cpu kernels. Tiles are claimed from the scheduler until there are
+//none left and sample is called with the position of each subsample, in subsamples from the
+//top left of the image
+pub(crate) fn render_tiles<F>(
+    options: &Options,
+    tiles: &TileScheduler,
+    sample: F,
+) -> Result<(), Cancelled>
+where
+    F: Fn(f64, f64) -> Orbit,
+{
+    render_tiles_batched(options, tiles, |sx, sy, orbits| {
+        for (i, orbit) in orbits.iter_mut().enumerate() {
+            *orbit = sample(sx[i], sy[i]);
+        }
+    })
+}
+
+//Same as render_tiles but sample_row is given a batch of subsample positions at once, with the
+//samples of each pixel next to each other, so kernels can work on several at a time
+pub(crate) fn render_tiles_batched<F>(
+    options: &Options,
+    tiles: &TileScheduler,
+    sample_row: F,
+) -> Result<(), Cancelled>
+where
+    F: Fn(&[f64], &[f64], &mut [Orbit]),
+{
+    let thread_id = options.thread_id.unwrap_or(0);
+    let mut batch = Batch::default();
+    let mut pixels = Vec::new();
+
+    while let Some(mut writer) = tiles.next_tile() {
+        let tile = writer.tile;
+
+        //Subdivision fills in whole rectangles so it can't be limited to a mask
+        if options.subdivide && !writer.masked() {
+            render_subdivided(options, &mut writer, &mut batch, &sample_row)?;
+            continue;
+        }
+
+        for iy in tile.y..tile.y + tile.height {
+            if options.cancel.is_cancelled() {
+                return Err(Cancelled);
+            }
+
+            pixels.clear();
+            pixels.extend(
+                (tile.x..tile.x + tile.width)
+                    .filter(|&ix| writer.wanted(ix, iy))
+                    .map(|ix| (ix, iy)),
+            );
+
+            for (&(ix, iy), &data) in pixels
+                .iter()
+                .zip(batch.render(options, &pixels, &sample_row))
+            {
+                writer.set(ix, iy, thread_id, data);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+//Scratch space for turning a list of pixels into subsample positions and the orbits back into
+//pixels, kept between batches to avoid reallocating
+#[derive(Default)]
+struct Batch {
+    sx: Vec<f64>,
+    sy: Vec<f64>,
+    orbits: Vec<Orbit>,
+    data: Vec<EscapeData>,
+}
+
+impl Batch {
+    fn render<F>(
+        &mut self,
+        options: &Options,
+        pixels: &[(u32, u32)],
+        sample_row: &F,
+    ) -> &[EscapeData]
+    where
+        F: Fn(&[f64], &[f64], &mut [Orbit]),
+    {
+        let per_pixel = (options.samples * options.samples) as usize;
+        self.sx.clear();
+        self.sy.clear();
+        for &(ix, iy) in pixels {
             for itery in 0..options.samples {
                 for iterx in 0..options.samples {
-                    let mut iter: u32 = 0;
-
-                    let x0: f64 = startx + (ix as f64 * options.samples as f64 + iterx as f64) * dx;
-                    let y0: f64 = starty + (iy as f64 * options.samples as f64 + itery as f64) * dy;
-                    let mut x: f64 = x0;
-                    let mut y: f64 = y0;
-                    let mut xtemp: f64;
-                    while x * x + y * y < (2 * 2) as f64 && iter <= options.max_iter {
-                        xtemp = x * x - y * y + x0;
-
-                        y = 2.0 * x * y + y0;
-                        x = xtemp;
-                        iter += 1;
-                    }
+                    self.sx
+                        .push(ix as f64 * options.samples as f64 + iterx as f64);
+                    self.sy
+                        .push(iy as f64 * options.samples as f64 + itery as f64);
+                }
+            }
+        }
+        self.orbits
+            .resize(pixels.len() * per_pixel, Orbit::default());
+        sample_row(&self.sx, &self.sy, &mut self.orbits);
 
-                    if iter <= options.max_iter {
-                        totaliter += iter;
+        self.data.clear();
+        for samples in self.orbits.chunks(per_pixel) {
+            let mut totaliter: u32 = 0;
+            let mut totalmag: f64 = 0.0;
+            let mut totalsmooth: f64 = 0.0;
+            let mut totaldistance: f64 = 0.0;
+            let mut totalaverage: f64 = 0.0;
+            let mut normal = (0.0, 0.0);
+            let mut escaped: u32 = 0;
+            let mut totaltrap = Closest {
+                distance: 0.0,
+                x: 0.0,
+                y: 0.0,
+            };
+
+            for orbit in samples {
+                if options.trap.is_some() {
+                    totaltrap.distance += orbit.trap.distance;
+                    totaltrap.x += orbit.trap.x;
+                    totaltrap.y += orbit.trap.y;
+                }
+                if orbit.iter <= options.max_iter {
+                    totaliter += orbit.iter;
+                    let mag = (orbit.x * orbit.x + orbit.y * orbit.y).sqrt();
+                    totalmag += mag;
+                    if options.newton.is_some() {
+                        let step = orbit.dx * orbit.dx + orbit.dy * orbit.dy;
+                        totalsmooth += newton::smooth_iter(orbit.iter, step);
+                    } else {
+                        let smooth =
+                            smooth_iter(orbit.iter, mag, options.bailout, options.exponent);
+                        totalsmooth += smooth;
+                        if options.average.is_some() {
+                            let fraction = (smooth - orbit.iter as f64).clamp(0.0, 1.0);
+                            totalaverage += orbit.average.mean(fraction);
+                        }
+                    }
+                    if options.distance && options.newton.is_none() {
+                        let dmag = (orbit.dx * orbit.dx + orbit.dy * orbit.dy).sqrt();
+                        totaldistance += mag * mag.ln() / dmag;
+                        //z / dz scaled by |dz|^2, only the direction is wanted
+                        let nx = orbit.x * orbit.dx + orbit.y * orbit.dy;
+                        let ny = orbit.y * orbit.dx - orbit.x * orbit.dy;
+                        let length = (nx * nx + ny * ny).sqrt();
+                        if length > 0.0 {
+                            normal.0 += nx / length;
+                            normal.1 += ny / length;
+                        }
                     }
+                    escaped += 1;
                 }
             }
 
-            sender
-                .send((
-                    iy * options.width + ix,
-                    iterations2colour(
-                        &options,
-                        totaliter / (options.samples * options.samples),
-                        options.max_iter,
-                        colour,
-                    ),
-                ))
-                .unwrap();
+            let normal_length = (normal.0 * normal.0 + normal.1 * normal.1)
+                .sqrt()
+                .max(f64::MIN_POSITIVE);
+            self.data.push(EscapeData {
+                iter: totaliter / (options.samples * options.samples),
+                inside: (escaped == 0) as u32,
+                mag: if escaped > 0 {
+                    totalmag / escaped as f64
+                } else {
+                    0.0
+                },
+                smooth: totalsmooth / (options.samples * options.samples) as f64,
+                distance: totaldistance / (options.samples * options.samples) as f64,
+                //The root most of the samples found
+                root: samples
+                    .iter()
+                    .map(|orbit| orbit.root)
+                    .max_by_key(|&root| samples.iter().filter(|orbit| orbit.root == root).count())
+                    .unwrap_or(0),
+                _padding: 0,
+                trap: totaltrap.distance / per_pixel as f64,
+                trap_x: totaltrap.x / per_pixel as f64,
+                trap_y: totaltrap.y / per_pixel as f64,
+                average: if escaped > 0 {
+                    totalaverage / escaped as f64
+                } else {
+                    0.0
+                },
+                normal_x: normal.0 / normal_length,
+                normal_y: normal.1 / normal_length,
+            });
         }
-        iy = interlocked_increment(current_line.clone());
+
+        &self.data
     }
 }
 
-pub fn opencl_mandelbrot(options: Options, vec: &mut Vec<u32>) -> ocl::Result<()> {
-    let src = r#"#define MAX_COLOURS 256
-
-inline unsigned int iterations2colour(unsigned int iter, unsigned int max_iter, unsigned int flags)
+//Mariani-Silver subdivision of a tile. The border of a rectangle is rendered and if all of it
+//has the same iteration count the inside is filled in with it, otherwise the rectangle is
+//split in two and each half is tried again. Exterior rectangles are only filled when the
+//colouring depends on nothing but the iteration count, smooth and distance colouring would
+//show the fill
+fn render_subdivided<F>(
+    options: &Options,
+    writer: &mut TileWriter,
+    batch: &mut Batch,
+    sample_row: &F,
+) -> Result<(), Cancelled>
+where
+    F: Fn(&[f64], &[f64], &mut [Orbit]),
 {
-    iter = (iter * MAX_COLOURS / max_iter) & (MAX_COLOURS - 1);
+    let tile = writer.tile;
+    let index = |x: u32, y: u32| ((y - tile.y) * tile.width + (x - tile.x)) as usize;
+    let mut done: Vec<Option<EscapeData>> = vec![None; (tile.width * tile.height) as usize];
+    let mut border = Vec::new();
+    let mut missing = Vec::new();
+    let mut rects = vec![(
+        tile.x,
+        tile.y,
+        tile.x + tile.width - 1,
+        tile.y + tile.height - 1,
+    )];
+
+    while let Some((x0, y0, x1, y1)) = rects.pop() {
+        if options.cancel.is_cancelled() {
+            return Err(Cancelled);
+        }
+
+        border.clear();
+        for x in x0..=x1 {
+            border.push((x, y0));
+            border.push((x, y1));
+        }
+        for y in y0 + 1..y1 {
+            border.push((x0, y));
+            border.push((x1, y));
+        }
+
+        missing.clear();
+        missing.extend(border.iter().filter(|&&(x, y)| done[index(x, y)].is_none()));
+        missing.dedup();
+        for (&(x, y), &data) in missing
+            .iter()
+            .zip(batch.render(options, &missing, sample_row))
+        {
+            done[index(x, y)] = Some(data);
+        }
+
+        //Nothing left inside the border
+        if x1 - x0 < 2 || y1 - y0 < 2 {
+            continue;
+        }
+
+        let first = done[index(x0, y0)].unwrap();
+        let uniform = border.iter().all(|&(x, y)| {
+            let data = done[index(x, y)].unwrap();
+            data.iter == first.iter && data.inside == first.inside && data.root == first.root
+        });
+        //Traps colour the inside of the set as well so nothing can be filled with one
+        let fillable = options.trap.is_none()
+            && (first.inside == 1
+                || !(options.smooth
+                    || options.distance
+                    || options.newton.is_some()
+                    || options.average.is_some()
+                    || options.lighting.is_some()));
+
+        if uniform && fillable {
+            for y in y0 + 1..y1 {
+                for x in x0 + 1..x1 {
+                    done[index(x, y)] = Some(first);
+                }
+            }
+        } else if x1 - x0 <= SUBDIVIDE_MIN && y1 - y0 <= SUBDIVIDE_MIN {
+            missing.clear();
+            for y in y0 + 1..y1 {
+                missing.extend((x0 + 1..x1).map(|x| (x, y)));
+            }
+            for (&(x, y), &data) in missing
+                .iter()
+                .zip(batch.render(options, &missing, sample_row))
+            {
+                done[index(x, y)] = Some(data);
+            }
+        } else if x1 - x0 >= y1 - y0 {
+            let mid = (x0 + x1) / 2;
+            rects.push((x0, y0, mid, y1));
+            rects.push((mid, y0, x1, y1));
+        } else {
+            let mid = (y0 + y1) / 2;
+            rects.push((x0, y0, x1, mid));
+            rects.push((x0, mid, x1, y1));
+        }
+    }
+
+    let thread_id = options.thread_id.unwrap_or(0);
+    for y in tile.y..tile.y + tile.height {
+        for x in tile.x..tile.x + tile.width {
+            writer.set(x, y, thread_id, done[index(x, y)].unwrap());
+        }
+    }
 
-    return (((flags & 4) << 14) | ((flags & 2) << 7) | (flags & 1)) * iter;
+    Ok(())
 }
 
-__kernel void mandelbrot(unsigned int iterations, double centrex, double centrey, double scaley, unsigned int samples, __global unsigned int* out)
+pub fn opencl_mandelbrot(options: Options, vec: &mut Vec<EscapeData>) -> Result<(), OpenClError> {
+    let src = r#"typedef struct
 {
-    unsigned int width = get_global_size(1);
-    unsigned int height = get_global_size(0);
+    unsigned int iter;
+    unsigned int inside;
+    double mag;
+    double smooth;
+    double distance;
+    unsigned int root;
+    unsigned int _padding;
+    double trap;
+    double trapX;
+    double trapY;
+    double average;
+    double normalX;
+    double normalY;
+} EscapeData;
+
+#define PERIODICITY_EPSILON 1e-15
+
+#define FORMULA_MANDELBROT 0
+#define FORMULA_BURNING_SHIP 1
+#define FORMULA_TRICORN 2
+#define FORMULA_CELTIC 3
+#define FORMULA_PERPENDICULAR 4
+#define FORMULA_BUFFALO 5
+
+inline int inMainBulbs(double x, double y)
+{
+    double xq = x - 0.25;
+    double q = xq * xq + y * y;
+    return q * (q + xq) <= 0.25 * y * y || (x + 1) * (x + 1) + y * y <= 0.0625;
+}
 
-    double scalex = scaley * width / height;
+inline double2 complexMul(double2 a, double2 b)
+{
+    return (double2)(a.x * b.x - a.y * b.y, a.x * b.y + a.y * b.x);
+}
 
+//z^n, whole powers are multiplied out by repeated squaring and anything else goes through polar form
+inline double2 complexPow(double2 z, double n)
+{
+    if (n >= 0 && n == floor(n))
+    {
+        double2 result = (double2)(1, 0);
+        for (unsigned int e = (unsigned int)n; e > 0; e >>= 1)
+        {
+            if (e & 1) result = complexMul(result, z);
+            z = complexMul(z, z);
+        }
+        return result;
+    }
+    double r = pow(z.x * z.x + z.y * z.y, n * 0.5);
+    double theta = atan2(z.y, z.x) * n;
+    return (double2)(r * cos(theta), r * sin(theta));
+}
+
+__kernel void mandelbrot(unsigned int width, unsigned int height, unsigned int iterations, double centrex, double centrey, double scaley, double scalex, double rotationCos, double rotationSin, unsigned int samples, double bailout, unsigned int interiorCheck, unsigned int distance, unsigned int julia, double juliax, double juliay, double exponent, unsigned int formula, __global EscapeData* out)
+{
     double dx = scalex / width / samples;
     double dy = scaley / height / samples;
 
-    double startx = centrex - scalex * 0.5f;
-    double starty = centrey - scaley * 0.5f;
+    //Top left corner, the view is turned about its centre
+    double startx = centrex - (scalex * 0.5f * rotationCos - scaley * 0.5f * rotationSin);
+    double starty = centrey - (scalex * 0.5f * rotationSin + scaley * 0.5f * rotationCos);
 
-    unsigned int ix = get_global_id(1);
-    unsigned int iy = get_global_id(0);
+    unsigned int ix = get_global_id(0);
+    unsigned int iy = get_global_id(1);
     int totalCalc = 0;
+    double totalMag = 0;
+    double totalSmooth = 0;
+    double totalDistance = 0;
+    unsigned int escaped = 0;
 
     for (unsigned int aay = 0; aay < samples; aay++)
     {
@@ -238,26 +1602,103 @@ __kernel void mandelbrot(unsigned int iterations, double centrex, double centrey
         {
             unsigned int iter = 0;
 
-            double x0 = startx + (ix * samples + aax) * dx;
-            double y0 = starty + (iy * samples + aay) * dy;
+            double ox = (ix * samples + aax) * dx;
+            double oy = (iy * samples + aay) * dy;
+            double x0 = startx + (ox * rotationCos - oy * rotationSin);
+            double y0 = starty + (ox * rotationSin + oy * rotationCos);
 
             double x = x0;
             double y = y0;
+            double dzx = 1;
+            double dzy = 0;
+
+            //Julia sets start at the pixel with a fixed c
+            double cx = julia ? juliax : x0;
+            double cy = julia ? juliay : y0;
+            double dc = julia ? 0 : 1;
+
+            int classic = !julia && exponent == 2 && formula == FORMULA_MANDELBROT;
+            if (interiorCheck && classic && inMainBulbs(x0, y0)) iter = iterations + 1;
 
-            while (x * x + y * y < (2 * 2) && iter <= iterations)
+            double checkx = x;
+            double checky = y;
+            unsigned int period = 0;
+            unsigned int checkLimit = 1;
+
+            while (x * x + y * y < bailout * bailout && iter <= iterations)
             {
-                double xtemp = x * x - y * y + x0;
+                //The abs and conjugate variants fold z before the power, dz is flipped with it
+                if ((formula == FORMULA_BURNING_SHIP || formula == FORMULA_BUFFALO || formula == FORMULA_PERPENDICULAR) && x < 0)
+                {
+                    x = -x;
+                    dzx = -dzx;
+                }
+                if (((formula == FORMULA_BURNING_SHIP || formula == FORMULA_BUFFALO) && y < 0) || formula == FORMULA_TRICORN || formula == FORMULA_PERPENDICULAR)
+                {
+                    y = -y;
+                    dzy = -dzy;
+                }
 
-                y = 2 * x * y + y0;
-                x = xtemp;
+                //z^n is taken as z^(n - 1) * z so the derivative can reuse z^(n - 1)
+                double2 p = exponent == 2 ? (double2)(x, y) : complexPow((double2)(x, y), exponent - 1);
+                double xtemp = p.x * x - p.y * y;
+
+                //Celtic and Buffalo take the abs of the real part, flipping the same part of dz with it
+                int flip = (formula == FORMULA_CELTIC || formula == FORMULA_BUFFALO) && xtemp < 0;
+                if (distance)
+                {
+                    double dzxtemp = exponent * (p.x * dzx - p.y * dzy);
+                    dzy = exponent * (p.x * dzy + p.y * dzx);
+                    dzx = (flip ? -dzxtemp : dzxtemp) + dc;
+                }
+
+                y = p.x * y + p.y * x + cy;
+                x = (flip ? -xtemp : xtemp) + cx;
                 iter += 1;
+
+                if (interiorCheck)
+                {
+                    if (fabs(x - checkx) < PERIODICITY_EPSILON && fabs(y - checky) < PERIODICITY_EPSILON)
+                    {
+                        iter = iterations + 1;
+                    }
+                    else if (++period == checkLimit)
+                    {
+                        period = 0;
+                        checkLimit *= 2;
+                        checkx = x;
+                        checky = y;
+                    }
+                }
             }
 
-            if (iter <= iterations) totalCalc += iter;
+            if (iter <= iterations)
+            {
+                double mag = sqrt(x * x + y * y);
+                totalCalc += iter;
+                totalMag += mag;
+                totalSmooth += iter + 1 - log2(log(mag) / log(bailout)) / log2(fabs(exponent));
+                if (distance) totalDistance += mag * log(mag) / sqrt(dzx * dzx + dzy * dzy);
+                escaped += 1;
+            }
         }
     }
 
-    out[iy * width + ix] = iterations2colour(totalCalc / (samples * samples), iterations, 7);
+    EscapeData data;
+    data.iter = totalCalc / (samples * samples);
+    data.inside = escaped == 0;
+    data.mag = escaped > 0 ? totalMag / escaped : 0;
+    data.smooth = totalSmooth / (samples * samples);
+    data.distance = totalDistance / (samples * samples);
+    data.root = 0;
+    data._padding = 0;
+    data.trap = 0;
+    data.trapX = 0;
+    data.trapY = 0;
+    data.average = 0;
+    data.normalX = 0;
+    data.normalY = 0;
+    out[iy * width + ix] = data;
 }"#;
 
     let pro_que = ProQue::builder()
@@ -265,25 +1706,50 @@ __kernel void mandelbrot(unsigned int iterations, double centrex, double centrey
         .dims((options.width, options.height))
         .build()?;
 
-    let buffer = pro_que.create_buffer::<u32>()?;
+    let buffer = pro_que.create_buffer::<EscapeData>()?;
+    let view = View::new(&options);
 
     let kernel = pro_que
         .kernel_builder("mandelbrot")
+        .arg(options.width)
+        .arg(options.height)
         .arg(options.max_iter)
-        .arg(options.centrex)
-        .arg(options.centrey)
-        .arg(options.scaley)
+        .arg(options.centrex_f64())
+        .arg(options.centrey_f64())
+        .arg(options.scaley_f64())
+        .arg(options.scalex_f64())
+        .arg(view.cos)
+        .arg(view.sin)
         .arg(options.samples)
+        .arg(options.bailout)
+        .arg(options.interior_check as u32)
+        .arg(options.distance as u32)
+        .arg(options.julia().is_some() as u32)
+        .arg(options.julia().unwrap_or_default().0)
+        .arg(options.julia().unwrap_or_default().1)
+        .arg(options.exponent)
+        .arg(options.formula as u32)
         .arg(&buffer)
         .build()?;
 
-    unsafe {
-        kernel.enq()?;
+    //Dispatch in bands of rows so a cancel doesn't have to wait for the whole image
+    for y in (0..options.height).step_by(OPENCL_CHUNK_ROWS as usize) {
+        if options.cancel.is_cancelled() {
+            return Err(OpenClError::Cancelled);
+        }
+
+        let rows = OPENCL_CHUNK_ROWS.min(options.height - y);
+        unsafe {
+            kernel
+                .cmd()
+                .global_work_offset((0, y))
+                .global_work_size((options.width, rows))
+                .enq()?;
+        }
+        pro_que.finish()?;
     }
 
     buffer.read(vec).enq()?;
-
-    //println!("The value at index [{}] is now '{}'!", 200007, vec[200007]);
     Ok(())
 }
 
@@ -296,16 +1762,24 @@ mod vulkan_mandelbrot {
 
 layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
 
-uint MAX_COLOURS = 256;
-uint iterations2colour(uint iter, uint max_iter, uint flags)
-{
-    iter = (iter * MAX_COLOURS / max_iter) & (MAX_COLOURS - 1);
-
-    return (((flags & 4) << 14) | ((flags & 2) << 7) | (flags & 1)) * iter;
-}
+struct EscapeData {
+    uint iter;
+    uint inside;
+    double mag;
+    double smooth;
+    double distance;
+    uint root;
+    uint _padding;
+    double trap;
+    double trapX;
+    double trapY;
+    double average;
+    double normalX;
+    double normalY;
+};
 
-layout(set = 0, binding = 0) buffer Data {
-    uint data[];
+layout(std430, set = 0, binding = 0) buffer Data {
+    EscapeData data[];
 } buf;
 
 layout(set = 1, binding = 0) buffer Opts {
@@ -315,61 +1789,194 @@ layout(set = 1, binding = 0) buffer Opts {
     uint iterations;
     uint yoffset;
     uint _manualOffset;
+    uint interiorCheck;
+    uint distance;
+    uint julia;
+    uint formula;
     double scaley;
     double centrex;
     double centrey;
+    double bailout;
+    double juliax;
+    double juliay;
+    double exponent;
+    double scalex;
+    double rotationCos;
+    double rotationSin;
 } opts;
 
+const double PERIODICITY_EPSILON = 1e-15lf;
+
+const uint FORMULA_MANDELBROT = 0;
+const uint FORMULA_BURNING_SHIP = 1;
+const uint FORMULA_TRICORN = 2;
+const uint FORMULA_CELTIC = 3;
+const uint FORMULA_PERPENDICULAR = 4;
+const uint FORMULA_BUFFALO = 5;
+
+bool inMainBulbs(double x, double y)
+{
+    double xq = x - 0.25;
+    double q = xq * xq + y * y;
+    return q * (q + xq) <= 0.25 * y * y || (x + 1) * (x + 1) + y * y <= 0.0625;
+}
+
+dvec2 complexMul(dvec2 a, dvec2 b)
+{
+    return dvec2(a.x * b.x - a.y * b.y, a.x * b.y + a.y * b.x);
+}
+
+//z^n, whole powers are multiplied out by repeated squaring and anything else goes through polar form
+dvec2 complexPow(dvec2 z, double n)
+{
+    if (n >= 0 && n == floor(n))
+    {
+        dvec2 result = dvec2(1, 0);
+        for (uint e = uint(n); e > 0; e >>= 1)
+        {
+            if ((e & 1) != 0) result = complexMul(result, z);
+            z = complexMul(z, z);
+        }
+        return result;
+    }
+    //GLSL has no double precision pow or trig so polar form drops to float
+    float r = pow(float(z.x * z.x + z.y * z.y), float(n) * 0.5);
+    float theta = atan(float(z.y), float(z.x)) * float(n);
+    return dvec2(r * cos(theta), r * sin(theta));
+}
+
 void main() {
     uint ix = gl_GlobalInvocationID.x;
     uint iy = gl_GlobalInvocationID.y + opts.yoffset;
-    double scalex = opts.scaley * opts.width / opts.height;
-
-    double dx = scalex / opts.width / opts.samples;
+    double dx = opts.scalex / opts.width / opts.samples;
     double dy = opts.scaley / opts.height / opts.samples;
 
-    double startx = opts.centrex - scalex * 0.5f;
-    double starty = opts.centrey - opts.scaley * 0.5f;
+    //Top left corner, the view is turned about its centre
+    double startx = opts.centrex - (opts.scalex * 0.5f * opts.rotationCos - opts.scaley * 0.5f * opts.rotationSin);
+    double starty = opts.centrey - (opts.scalex * 0.5f * opts.rotationSin + opts.scaley * 0.5f * opts.rotationCos);
     int totalCalc = 0;
- 
+    double totalMag = 0;
+    double totalSmooth = 0;
+    double totalDistance = 0;
+    uint escaped = 0;
+
     for (uint aay = 0; aay < opts.samples; aay++)
     {
         for (uint aax = 0; aax < opts.samples; aax++)
         {
             uint iter = 0;
 
-            double x0 = startx + (ix * opts.samples + aax) * dx;
-            double y0 = starty + (iy * opts.samples + aay) * dy;
+            double ox = (ix * opts.samples + aax) * dx;
+            double oy = (iy * opts.samples + aay) * dy;
+            double x0 = startx + (ox * opts.rotationCos - oy * opts.rotationSin);
+            double y0 = starty + (ox * opts.rotationSin + oy * opts.rotationCos);
 
             double x = x0;
             double y = y0;
+            double dzx = 1;
+            double dzy = 0;
+
+            //Julia sets start at the pixel with a fixed c
+            bool julia = opts.julia != 0;
+            double cx = julia ? opts.juliax : x0;
+            double cy = julia ? opts.juliay : y0;
+            double dc = julia ? 0 : 1;
 
-            while (x * x + y * y < (2 * 2) && iter <= opts.iterations)
+            uint formula = opts.formula;
+            bool classic = !julia && opts.exponent == 2 && formula == FORMULA_MANDELBROT;
+            if (opts.interiorCheck != 0 && classic && inMainBulbs(x0, y0)) iter = opts.iterations + 1;
+
+            double checkx = x;
+            double checky = y;
+            uint period = 0;
+            uint checkLimit = 1;
+
+            while (x * x + y * y < opts.bailout * opts.bailout && iter <= opts.iterations)
             {
-                double xtemp = x * x - y * y + x0;
+                //The abs and conjugate variants fold z before the power, dz is flipped with it
+                if ((formula == FORMULA_BURNING_SHIP || formula == FORMULA_BUFFALO || formula == FORMULA_PERPENDICULAR) && x < 0)
+                {
+                    x = -x;
+                    dzx = -dzx;
+                }
+                if (((formula == FORMULA_BURNING_SHIP || formula == FORMULA_BUFFALO) && y < 0) || formula == FORMULA_TRICORN || formula == FORMULA_PERPENDICULAR)
+                {
+                    y = -y;
+                    dzy = -dzy;
+                }
+
+                //z^n is taken as z^(n - 1) * z so the derivative can reuse z^(n - 1)
+                dvec2 p = opts.exponent == 2 ? dvec2(x, y) : complexPow(dvec2(x, y), opts.exponent - 1);
+                double xtemp = p.x * x - p.y * y;
+
+                //Celtic and Buffalo take the abs of the real part, flipping the same part of dz with it
+                bool flip = (formula == FORMULA_CELTIC || formula == FORMULA_BUFFALO) && xtemp < 0;
+                if (opts.distance != 0)
+                {
+                    double dzxtemp = opts.exponent * (p.x * dzx - p.y * dzy);
+                    dzy = opts.exponent * (p.x * dzy + p.y * dzx);
+                    dzx = (flip ? -dzxtemp : dzxtemp) + dc;
+                }
 
-                y = 2 * x * y + y0;
-                x = xtemp;
+                y = p.x * y + p.y * x + cy;
+                x = (flip ? -xtemp : xtemp) + cx;
                 iter += 1;
+
+                if (opts.interiorCheck != 0)
+                {
+                    if (abs(x - checkx) < PERIODICITY_EPSILON && abs(y - checky) < PERIODICITY_EPSILON)
+                    {
+                        iter = opts.iterations + 1;
+                    }
+                    else if (++period == checkLimit)
+                    {
+                        period = 0;
+                        checkLimit *= 2;
+                        checkx = x;
+                        checky = y;
+                    }
+                }
             }
 
-            if (iter <= opts.iterations) totalCalc += int(iter);
+            if (iter <= opts.iterations)
+            {
+                double mag = sqrt(x * x + y * y);
+                totalCalc += int(iter);
+                totalMag += mag;
+                //GLSL has no double precision log so drop to float for the smoothing term
+                totalSmooth += iter + 1 - log2(log(float(mag)) / log(float(opts.bailout))) / log2(abs(float(opts.exponent)));
+                if (opts.distance != 0) totalDistance += mag * log(float(mag)) / sqrt(dzx * dzx + dzy * dzy);
+                escaped += 1;
+            }
         }
     }
 
-    buf.data[(iy - (opts.yoffset)) * opts.width + ix] = iterations2colour(totalCalc / (opts.samples * opts.samples), opts.iterations, 7);
+    EscapeData data;
+    data.iter = uint(totalCalc) / (opts.samples * opts.samples);
+    data.inside = escaped == 0 ? 1 : 0;
+    data.mag = escaped > 0 ? totalMag / escaped : 0.0lf;
+    data.smooth = totalSmooth / (opts.samples * opts.samples);
+    data.distance = totalDistance / (opts.samples * opts.samples);
+    data.root = 0;
+    data._padding = 0;
+    data.trap = 0.0lf;
+    data.trapX = 0.0lf;
+    data.trapY = 0.0lf;
+    data.average = 0.0lf;
+    data.normalX = 0.0lf;
+    data.normalY = 0.0lf;
+    buf.data[(iy - (opts.yoffset)) * opts.width + ix] = data;
 }
 "
     }
 }
 
-pub fn vulkan_mandelbrot(options: Options, vec: &mut [u32]) {
+pub fn vulkan_mandelbrot(options: Options, vec: &mut [EscapeData]) -> Result<(), VulkanError> {
     if options.height % options.vulkan_chunks != 0 {
-        println!(
+        return Err(VulkanError::Unsupported(format!(
             "Cannot run vulkan with height not divisible by chunks ({})",
             options.vulkan_chunks
-        );
-        return;
+        )));
     }
     let library = VulkanLibrary::new().expect("no local Vulkan library/DLL");
     let instance =
@@ -400,22 +2007,24 @@ pub fn vulkan_mandelbrot(options: Options, vec: &mut [u32]) {
         }
     }
 
-    if best_index.is_none() {
-        println!("GPU has no valid queues, exiting..");
-        return;
-    }
-    let queue_family_index = best_index.unwrap() as u32;
+    let queue_family_index = match best_index {
+        Some(index) => index as u32,
+        None => {
+            return Err(VulkanError::Unsupported(
+                "GPU has no valid queues".to_string(),
+            ))
+        }
+    };
 
     let f64_features = Features {
         shader_float64: true,
         ..Features::empty()
     };
 
-    if physical.supported_features().contains(&f64_features) {
-        println!("Supports f64");
-    } else {
-        println!("GPU doesn't support f64 exiting");
-        return;
+    if !physical.supported_features().contains(&f64_features) {
+        return Err(VulkanError::Unsupported(
+            "GPU doesn't support f64".to_string(),
+        ));
     }
 
     let (device, mut queues) = Device::new(
@@ -433,7 +2042,8 @@ pub fn vulkan_mandelbrot(options: Options, vec: &mut [u32]) {
 
     let queue = queues.next().unwrap();
 
-    let data = 0..(options.width * options.height / options.vulkan_chunks);
+    let data = (0..(options.width * options.height / options.vulkan_chunks))
+        .map(|_| EscapeData::default());
 
     let allocator = GenericMemoryAllocator::<Arc<FreeListAllocator>>::new_default(device.clone());
     let data_buffer = Buffer::from_iter(
@@ -452,6 +2062,10 @@ pub fn vulkan_mandelbrot(options: Options, vec: &mut [u32]) {
     .unwrap();
 
     for chunk in 0..options.vulkan_chunks {
+        if options.cancel.is_cancelled() {
+            return Err(VulkanError::Cancelled);
+        }
+
         let mut opts = options.as_vulkan_opts();
         opts.yoffset = options.height / options.vulkan_chunks * chunk;
         let opts_buffer = Buffer::from_data(
@@ -546,4 +2160,78 @@ pub fn vulkan_mandelbrot(options: Options, vec: &mut [u32]) {
             vec[i + (opts.yoffset as usize * opts.width as usize)] = *val;
         }
     }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edge_mask_marks_both_sides_of_an_edge() {
+        let mut options = Options {
+            width: 4,
+            height: 3,
+            ..Options::default()
+        };
+        let mut field = IterationField::new(&options);
+        #[rustfmt::skip]
+        let smooth = [
+            1.0, 1.5, 1.0, 5.0,
+            1.0, 1.0, 1.0, 1.0,
+            1.0, 9.0, 1.0, 1.0,
+        ];
+        for (data, smooth) in field.data.iter_mut().zip(smooth) {
+            data.smooth = smooth;
+        }
+        field.data[9].inside = 1;
+
+        //A difference of exactly the threshold isn't an edge, the 5 at the end of the first row
+        //isn't compared with the start of the second and the inside pixel marks all around it
+        #[rustfmt::skip]
+        let expected = [
+            false, false, true, true,
+            false, true, false, true,
+            true, true, true, false,
+        ];
+        options.adaptive_threshold = 0.5;
+        assert_eq!(field.edge_mask(&options), expected);
+
+        //With a threshold above every difference only the inside pixel is left
+        #[rustfmt::skip]
+        let expected = [
+            false, false, false, false,
+            false, true, false, false,
+            true, true, true, false,
+        ];
+        options.adaptive_threshold = 10.0;
+        assert_eq!(field.edge_mask(&options), expected);
+    }
+
+    #[test]
+    fn edge_mask_follows_averages_and_traps() {
+        //A threshold of 0.5 iterations out of 100 is 0.005 of the palette
+        let mut options = Options {
+            width: 4,
+            height: 1,
+            max_iter: 100,
+            adaptive_threshold: 0.5,
+            average: Some(Average::Triangle),
+            ..Options::default()
+        };
+        let mut field = IterationField::new(&options);
+        for (data, average) in field.data.iter_mut().zip([0.1, 0.104, 0.3, 0.3]) {
+            data.average = average;
+        }
+        assert_eq!(field.edge_mask(&options), [false, true, true, false]);
+
+        //Trap distances go through the same square root as the colouring
+        options.average = None;
+        options.trap = Trap::from_name("point", 0.0, 0.0, 0.0, 1.0);
+        for (data, distance) in field.data.iter_mut().zip([0.25, 0.25, 0.26, 0.26]) {
+            data.trap = distance;
+        }
+        assert_eq!(field.edge_mask(&options), [false, true, true, false]);
+    }
 }
@@ -0,0 +1,155 @@
+//3D lighting of the outside of the set. The distance estimate, or the smooth iteration count
+//without one, is treated as a height field and lit with Lambert diffuse and Blinn-Phong
+//specular light, which is then laid over the palette colour. The distance estimate's gradient
+//is the direction of z / dz with a length of about 1, so with distance on the normals come
+//straight from the orbit, otherwise they are taken from the neighbouring pixels. The light is
+//fixed to the image so either way it doesn't turn with the view
+use crate::{EscapeData, IterationField, Options};
+
+pub const DEFAULT_LIGHT_AZIMUTH: f64 = 45.0;
+pub const DEFAULT_LIGHT_ELEVATION: f64 = 45.0;
+pub const DEFAULT_LIGHT_AMBIENT: f64 = 0.2;
+pub const DEFAULT_LIGHT_DEPTH: f64 = 1.0;
+
+//Brightness and sharpness of the highlights
+const SPECULAR: f64 = 0.4;
+const SHININESS: f64 = 32.0;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Lighting {
+    //Direction the light comes from in degrees, anticlockwise from the right of the image
+    pub azimuth: f64,
+    //Height of the light in degrees, 90 is straight above
+    pub elevation: f64,
+    //Light every pixel gets however it faces
+    pub ambient: f64,
+    //Scale of the height field, higher makes slopes steeper
+    pub depth: f64,
+}
+
+impl Default for Lighting {
+    fn default() -> Self {
+        Self {
+            azimuth: DEFAULT_LIGHT_AZIMUTH,
+            elevation: DEFAULT_LIGHT_ELEVATION,
+            ambient: DEFAULT_LIGHT_AMBIENT,
+            depth: DEFAULT_LIGHT_DEPTH,
+        }
+    }
+}
+
+impl Lighting {
+    //Unit vector towards the light with x to the right, y down the image and z out of it
+    fn direction(&self) -> [f64; 3] {
+        let (sin_azimuth, cos_azimuth) = self.azimuth.to_radians().sin_cos();
+        let (sin_elevation, cos_elevation) = self.elevation.to_radians().sin_cos();
+        [
+            cos_azimuth * cos_elevation,
+            -sin_azimuth * cos_elevation,
+            sin_elevation,
+        ]
+    }
+
+    //Diffuse and specular light of every pixel, inside pixels are left as they are
+    pub fn light(&self, options: &Options, field: &IterationField) -> Vec<(f64, f64)> {
+        let light = self.direction();
+        //Halfway between the light and a viewer looking straight down
+        let half = normalise([light[0], light[1], light[2] + 1.0]);
+        let height = |data: &EscapeData| {
+            if options.distance {
+                data.distance / field.pixel_size
+            } else {
+                data.smooth
+            }
+        };
+
+        //The orbit's normal is in the complex plane, turning it back by the rotation and
+        //stretching it by the sides of a pixel gives the slope across the image in pixels
+        let (sin, cos) = options.rotation.to_radians().sin_cos();
+        let stretch_x = options.scalex_f64() / options.width as f64 / field.pixel_size;
+        let stretch_y = options.scaley_f64() / options.height as f64 / field.pixel_size;
+
+        let width = field.width as usize;
+        let height_at = |x: usize, y: usize, fallback: f64| {
+            let data = &field.data[y * width + x];
+            if data.inside != 0 {
+                fallback
+            } else {
+                height(data)
+            }
+        };
+
+        field
+            .data
+            .iter()
+            .enumerate()
+            .map(|(i, data)| {
+                if data.inside != 0 {
+                    return (1.0, 0.0);
+                }
+
+                //Only the cpu kernels fill in the normal, the gpu ones fall back to the pixels
+                let (gx, gy) = if data.normal_x != 0.0 || data.normal_y != 0.0 {
+                    let (nx, ny) = (data.normal_x, data.normal_y);
+                    (
+                        (nx * cos + ny * sin) * stretch_x,
+                        (ny * cos - nx * sin) * stretch_y,
+                    )
+                } else {
+                    //Central differences, neighbours that are off the image or inside the set
+                    //count as the same height as this pixel
+                    let (x, y) = (i % width, i / width);
+                    let here = height(data);
+                    let left = if x > 0 {
+                        height_at(x - 1, y, here)
+                    } else {
+                        here
+                    };
+                    let right = if x + 1 < width {
+                        height_at(x + 1, y, here)
+                    } else {
+                        here
+                    };
+                    let up = if y > 0 {
+                        height_at(x, y - 1, here)
+                    } else {
+                        here
+                    };
+                    let down = if y + 1 < field.height as usize {
+                        height_at(x, y + 1, here)
+                    } else {
+                        here
+                    };
+                    ((right - left) * 0.5, (down - up) * 0.5)
+                };
+
+                let normal = normalise([-gx * self.depth, -gy * self.depth, 1.0]);
+                let diffuse = dot(normal, light).max(0.0);
+                let specular = if diffuse > 0.0 {
+                    SPECULAR * dot(normal, half).max(0.0).powf(SHININESS)
+                } else {
+                    0.0
+                };
+                (self.ambient + (1.0 - self.ambient) * diffuse, specular)
+            })
+            .collect()
+    }
+}
+
+//Palette colour scaled by the diffuse light with the white highlight added on top
+pub fn shade(colour: [u8; 3], (diffuse, specular): (f64, f64)) -> [u8; 3] {
+    colour.map(|channel| {
+        (channel as f64 * diffuse + specular * 255.0)
+            .round()
+            .clamp(0.0, 255.0) as u8
+    })
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn normalise(v: [f64; 3]) -> [f64; 3] {
+    let length = dot(v, v).sqrt();
+    v.map(|component| component / length)
+}
@@ -1,20 +1,36 @@
 #[macro_use]
 extern crate rocket;
-use argparse::{ArgumentParser, Store, StoreTrue};
-use image::{ImageBuffer, RgbImage};
-use mandelbrot::Options;
+use argparse::{ArgumentParser, List, Store, StoreFalse, StoreTrue};
+use image::RgbImage;
+use mandelbrot::average::{self, Average, AVERAGES};
+use mandelbrot::buddhabrot::{self, Buddhabrot};
+use mandelbrot::lighting::{self, Lighting};
+use mandelbrot::newton::{self, Newton};
+use mandelbrot::palette::{self, Palette};
+use mandelbrot::perturbation::ReferenceOrbit;
+use mandelbrot::progressive;
+use mandelbrot::tiles::TileScheduler;
+use mandelbrot::trap::{self, Trap, TRAPS};
+use mandelbrot::{
+    CancelToken, Cancelled, Decimal, Formula, Fractal, IterationField, OpenClError, Options,
+    Precision, VulkanError, FORMULAS,
+};
 use pbr::ProgressBar;
 use rocket::fairing::{Fairing, Info, Kind};
 use rocket::fs::{relative, FileServer};
 use rocket::http::Header;
-use rocket::{Request, Response};
+use rocket::response::stream::TextStream;
+use rocket::tokio::sync::mpsc;
+use rocket::tokio::task;
+use rocket::{Either, Request, Response};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::path::Path;
-use std::sync::mpsc;
-use std::sync::{Arc, Mutex};
-use std::thread;
 use std::time::Instant;
 
 const DEFAULT_FILENAME: &str = "output.bmp";
+//Distance in pixels that maps to white in a written distance field
+const DISTANCE_FIELD_RANGE: f64 = 16.0;
 
 pub struct CORS;
 
@@ -38,42 +54,132 @@ impl Fairing for CORS {
     }
 }
 
-fn generate(options: Options, out: &mut Vec<u32>) {
+//Render into field, with progressive set on_preview gets each coarse preview before the field
+//is finished. If options.cancel is used the field is only complete when this returns Ok
+fn generate<P>(
+    options: &Options,
+    field: &mut IterationField,
+    mut on_preview: P,
+) -> Result<(), Cancelled>
+where
+    P: FnMut(u32, &IterationField),
+{
     println!("{}", options);
     let start = Instant::now();
 
+    //The higher precision kernels only run on the cpu so forcing one takes priority over the
+    //gpu flags, if it was picked automatically the gpu still runs but can't resolve the view
+    let precision = options.precision();
+    if options.newton.is_some() && (options.perturbation || options.double_double) {
+        println!("Newton's method only runs in f64, ignoring precision flags");
+    } else if options.exponent != 2.0 && (options.perturbation || options.double_double) {
+        println!("Only f64 supports exponents other than 2, ignoring precision flags");
+    } else if options.perturbation && precision != Precision::Perturbation {
+        println!(
+            "Perturbation only supports the Mandelbrot set, using {:?}",
+            precision
+        );
+    }
+    let forced = (options.perturbation || options.double_double) && precision != Precision::Double;
+    let cpu_only = if options.newton.is_some() {
+        Some("Newton's method")
+    } else if options.trap.is_some() {
+        Some("Orbit trap colouring")
+    } else if options.average.is_some() {
+        Some("Average colouring")
+    } else {
+        None
+    };
+    let gpu = (options.ocl || options.vulkan) && !forced && cpu_only.is_none();
+    if options.ocl || options.vulkan {
+        if let Some(feature) = cpu_only {
+            println!(
+                "{} only runs on the cpu, ignoring opencl and vulkan flags",
+                feature
+            );
+        } else if forced {
+            println!(
+                "{:?} only runs on the cpu, ignoring opencl and vulkan flags",
+                precision
+            );
+        } else if precision != Precision::Double {
+            println!(
+                "View is too deep for f64 on the gpu, run on the cpu to use {:?}",
+                precision
+            );
+        }
+    }
+
     //Run the opencl version and return
-    if options.ocl {
+    if options.ocl && gpu {
         println!(
             "Running opencl version threads flag will be ignored and no progress bar can be shown"
         );
-        mandelbrot::opencl_mandelbrot(options, out).expect("Failed to generate image with opencl");
+        match mandelbrot::opencl_mandelbrot(options.clone(), &mut field.data) {
+            Err(OpenClError::Cancelled) => return Err(Cancelled),
+            result => result.expect("Failed to generate image with opencl"),
+        }
         println!("time taken: {}ms", start.elapsed().as_millis());
-        return;
-    } else if options.vulkan {
+        return Ok(());
+    } else if options.vulkan && gpu {
         println!(
             "Running vulkan version threads flag will be ignored and no progress bar can be shown"
         );
-        mandelbrot::vulkan_mandelbrot(options, out);
+        match mandelbrot::vulkan_mandelbrot(options.clone(), &mut field.data) {
+            Err(VulkanError::Cancelled) => return Err(Cancelled),
+            result => result.expect("Failed to generate image with vulkan"),
+        }
         println!("time taken: {}ms", start.elapsed().as_millis());
-        return;
+        return Ok(());
     }
 
-    let current_line = Arc::new(Mutex::new(0));
-    let (tx, rx) = mpsc::channel();
+    //The reference orbit is shared by every thread so only compute it once
+    let reference = if precision == Precision::Perturbation {
+        Some(ReferenceOrbit::new(options))
+    } else {
+        None
+    };
+
+    let reference = reference.as_ref();
+    if options.progressive {
+        progressive::render(options, field, reference, |step, preview| {
+            if step > 1 {
+                on_preview(step, preview);
+            }
+        })?;
+    } else if options.adaptive && options.samples > 1 {
+        //Find the edges from a single sample per pixel then go back and supersample just them
+        let mut preview = options.clone();
+        preview.samples = 1;
+        render_cpu(
+            &preview,
+            &TileScheduler::new(field, options.tile_size),
+            reference,
+        )?;
 
-    for i in 0..options.threads {
-        let mut local_options = options;
-        local_options.thread_id = Some(i);
-        let local_tx = mpsc::Sender::clone(&tx);
-        let current_line_ref = Arc::clone(&current_line);
-        thread::spawn(move || mandelbrot::mandelbrot(local_options, local_tx, current_line_ref));
+        let mask = field.edge_mask(options);
+        println!(
+            "Supersampling {} of {} pixels",
+            mask.iter().filter(|&&edge| edge).count(),
+            mask.len()
+        );
+        let tiles = TileScheduler::with_mask(field, options.tile_size, mask);
+        render_cpu(options, &tiles, reference)?;
+    } else {
+        render_cpu(
+            options,
+            &TileScheduler::new(field, options.tile_size),
+            reference,
+        )?;
     }
 
-    //Drop tx because we only need it for cloning and if we don't drop it the loop below will never end
-    drop(tx);
+    println!("time taken: {}ms", start.elapsed().as_millis());
+    Ok(())
+}
 
-    let mut pb = ProgressBar::new(100);
+//Progress bar that is only drawn with --progress
+fn progress_bar(options: &Options, total: usize) -> ProgressBar<std::io::Stdout> {
+    let mut pb = ProgressBar::new(total as u64);
     pb.show_bar = options.progress;
     pb.show_counter = options.progress;
     pb.show_message = options.progress;
@@ -81,24 +187,95 @@ fn generate(options: Options, out: &mut Vec<u32>) {
     pb.show_speed = false;
     pb.show_time_left = false;
     pb.show_tick = false;
-    let mut pos = 0;
-    for (i, val) in rx {
-        pos += 1;
-        if pos % (options.width * options.height / 100) == 0 {
-            pb.inc();
-        }
-        out[i as usize] = val;
+    pb
+}
+
+//Run the cpu kernels over every tile in the scheduler with a progress bar
+fn render_cpu(
+    options: &Options,
+    tiles: &TileScheduler,
+    reference: Option<&ReferenceOrbit>,
+) -> Result<(), Cancelled> {
+    let mut pb = progress_bar(options, tiles.len());
+    let result = mandelbrot::render_cpu(options, tiles, reference, |completed, _| {
+        pb.set(completed as u64);
+    });
+    pb.finish_print(if result.is_ok() { "done" } else { "cancelled" });
+    result
+}
+
+//Orbit density version of generate, it is tone mapped straight to an image as there is no
+//iteration field to colour
+fn generate_buddhabrot(options: &Options, buddhabrot: &Buddhabrot) -> Result<RgbImage, Cancelled> {
+    println!("{}", options);
+    let start = Instant::now();
+    if options.ocl || options.vulkan {
+        println!("Orbit density only runs on the cpu, ignoring opencl and vulkan flags");
     }
-    pb.finish_print("done");
 
-    //mandelbrot::mandelbrot(options, out);
+    let mut pb = progress_bar(options, buddhabrot.rows(options));
+    let density = buddhabrot::render(options, buddhabrot, |completed, _| {
+        pb.set(completed as u64);
+    });
+    pb.finish_print(if density.is_ok() { "done" } else { "cancelled" });
+    let img = density?.to_image(buddhabrot, &options.palette, options.max_colours);
+
     println!("time taken: {}ms", start.elapsed().as_millis());
+    Ok(img)
 }
 
-#[get(
-    "/?<max_iter>&<width>&<height>&<threads>&<ocl>&<samples>&<scale>&<x>&<y>&<colourise>&<vulkan>"
-)]
-fn mandelbrot_rest(
+//The smooth count divides by log2 |exponent| so it needs a magnitude that grows each iteration,
+//and orbits of negative powers don't head off to infinity the way smoothing, distance estimation
+//and lighting assume, so those combinations are turned down rather than coloured with NaNs
+fn check_exponent(options: &Options) -> Result<(), String> {
+    if options.exponent.abs() <= 1.0 || options.exponent.is_nan() {
+        return Err(String::from("exponent must be above 1 or below -1"));
+    }
+    if options.exponent < 0.0 && (options.smooth || options.distance || options.lighting.is_some())
+    {
+        return Err(String::from(
+            "negative exponents can't be used with smooth colouring, distance estimation or lighting",
+        ));
+    }
+    Ok(())
+}
+
+//Orbits inside the radius 2 circle can still be bounded, and the smooth count divides by ln bailout
+//so a smaller radius would misclassify orbits or colour them with NaNs
+fn check_bailout(options: &Options) -> Result<(), String> {
+    if options.bailout < 2.0 || options.bailout.is_nan() {
+        return Err(String::from("bailout must be at least 2"));
+    }
+    Ok(())
+}
+
+//Compared as a decimal so deep zooms whose scale underflows f64 still pass
+fn check_scale(options: &Options) -> Result<(), String> {
+    if options.scaley <= Decimal::ZERO {
+        return Err(String::from("scale must be more than 0"));
+    }
+    Ok(())
+}
+
+//The Buddhabrot only iterates z^2 + c, see buddhabrot. The REST api can't ask for one yet but
+//shares the check so it can't be missed once it does
+fn check_buddhabrot(options: &Options) -> Result<(), String> {
+    if options.buddhabrot.is_some()
+        && (options.fractal != Fractal::Mandelbrot
+            || options.formula != Formula::Mandelbrot
+            || options.exponent != 2.0
+            || options.newton.is_some())
+    {
+        return Err(String::from(
+            "the buddhabrot only works with the z^2 + c Mandelbrot set, not Julia sets, other formulas or exponents, or Newton's method",
+        ));
+    }
+    Ok(())
+}
+
+//Every setting the REST api takes, all optional. Parameters left out keep their defaults
+#[derive(FromForm, Debug)]
+struct RenderQuery {
     max_iter: Option<u32>,
     width: Option<u32>,
     height: Option<u32>,
@@ -107,68 +284,344 @@ fn mandelbrot_rest(
     vulkan: Option<bool>,
     colourise: Option<bool>,
     samples: Option<u32>,
-    scale: Option<f64>,
-    x: Option<f64>,
-    y: Option<f64>,
-) -> String {
+    scale: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+    smooth: Option<bool>,
+    bailout: Option<f64>,
+    palette: Option<String>,
+    offset: Option<f64>,
+    repeat: Option<f64>,
+    reverse: Option<bool>,
+    histogram: Option<bool>,
+    distance: Option<bool>,
+    interior_check: Option<bool>,
+    perturbation: Option<bool>,
+    double_double: Option<bool>,
+    simd: Option<bool>,
+    adaptive: Option<bool>,
+    adaptive_threshold: Option<f64>,
+    subdivide: Option<bool>,
+    progressive: Option<bool>,
+    julia_re: Option<f64>,
+    julia_im: Option<f64>,
+    exponent: Option<f64>,
+    formula: Option<String>,
+    newton: Option<String>,
+    nova: Option<bool>,
+    relaxation: Option<f64>,
+    trap: Option<String>,
+    trap_re: Option<f64>,
+    trap_im: Option<f64>,
+    trap_angle: Option<f64>,
+    trap_size: Option<f64>,
+    average: Option<String>,
+    stripe_density: Option<f64>,
+    light: Option<bool>,
+    light_azimuth: Option<f64>,
+    light_elevation: Option<f64>,
+    light_ambient: Option<f64>,
+    light_depth: Option<f64>,
+    rotation: Option<f64>,
+    xmin: Option<String>,
+    xmax: Option<String>,
+    ymin: Option<String>,
+    ymax: Option<String>,
+}
+
+impl RenderQuery {
+    //Cache filename covering every parameter, so a new one can't be left out of it. The hash
+    //only has to stay the same while the server runs, a new build just renders images again
+    fn filename(&self) -> String {
+        let mut hasher = DefaultHasher::new();
+        format!("{:?}", self).hash(&mut hasher);
+        format!("images/{:016x}.png", hasher.finish())
+    }
+}
+
+#[get("/?<query..>")]
+async fn mandelbrot_rest(query: RenderQuery) -> Either<String, TextStream![String]> {
+    let filename = query.filename();
     let mut options = Options::default();
     options.service = true;
-    options.max_iter = max_iter.unwrap_or(options.max_iter);
-    options.width = width.unwrap_or(options.width);
-    options.height = height.unwrap_or(options.height);
-    options.centrex = x.unwrap_or(options.centrex);
-    options.centrey = y.unwrap_or(options.centrey);
-    options.samples = samples.unwrap_or(options.samples);
-    options.colourise = colourise.unwrap_or(options.colourise);
-    options.threads = threads.unwrap_or(options.threads);
-    options.ocl = ocl.unwrap_or(options.ocl);
-    options.vulkan = vulkan.unwrap_or(options.vulkan);
-    options.scaley = scale.unwrap_or(options.scaley);
-
-    let filename = format!(
-        "images/{}-{}-{}-{}-{}-{}-{}-{}-{}-{}.png",
-        options.width,
-        options.height,
-        options.max_iter,
-        options.max_colours,
-        options.centrex,
-        options.centrey,
-        options.scaley,
-        options.samples,
-        options.colour,
-        options.colourise
-    );
+    options.max_iter = query.max_iter.unwrap_or(options.max_iter);
+    options.width = query.width.unwrap_or(options.width);
+    options.height = query.height.unwrap_or(options.height);
+    //Coordinates are taken as strings so deep zoom locations keep all of their digits
+    for (value, target) in [
+        (query.x, &mut options.centrex),
+        (query.y, &mut options.centrey),
+        (query.scale, &mut options.scaley),
+    ] {
+        if let Some(value) = value {
+            match value.parse() {
+                Ok(value) => *target = value,
+                Err(_) => return Either::Left(format!("Error: invalid number {}", value)),
+            }
+        }
+    }
+    //Corners replace the centre and scale, all four are needed
+    match (query.xmin, query.xmax, query.ymin, query.ymax) {
+        (None, None, None, None) => {}
+        (Some(xmin), Some(xmax), Some(ymin), Some(ymax)) => {
+            let mut bounds: Vec<Decimal> = Vec::with_capacity(4);
+            for value in [xmin, xmax, ymin, ymax] {
+                match value.parse() {
+                    Ok(value) => bounds.push(value),
+                    Err(_) => return Either::Left(format!("Error: invalid number {}", value)),
+                }
+            }
+            if bounds[0] >= bounds[1] || bounds[2] >= bounds[3] {
+                return Either::Left(String::from(
+                    "Error: xmin and ymin have to be less than xmax and ymax",
+                ));
+            }
+            options.set_bounds(&bounds[0], &bounds[1], &bounds[2], &bounds[3]);
+        }
+        _ => {
+            return Either::Left(String::from(
+                "Error: bounds need all of xmin, xmax, ymin and ymax",
+            ))
+        }
+    }
+    options.rotation = query.rotation.unwrap_or(options.rotation);
+    options.samples = query.samples.unwrap_or(options.samples);
+    options.colourise = query.colourise.unwrap_or(options.colourise);
+    options.threads = query.threads.unwrap_or(options.threads);
+    options.ocl = query.ocl.unwrap_or(options.ocl);
+    options.vulkan = query.vulkan.unwrap_or(options.vulkan);
+    options.smooth = query.smooth.unwrap_or(options.smooth);
+    options.bailout = query.bailout.unwrap_or(options.bailout);
+    options.histogram = query.histogram.unwrap_or(options.histogram);
+    options.distance = query.distance.unwrap_or(options.distance);
+    options.interior_check = query.interior_check.unwrap_or(options.interior_check);
+    options.perturbation = query.perturbation.unwrap_or(options.perturbation);
+    options.double_double = query.double_double.unwrap_or(options.double_double);
+    options.simd = query.simd.unwrap_or(options.simd);
+    options.adaptive = query.adaptive.unwrap_or(options.adaptive);
+    options.adaptive_threshold = query
+        .adaptive_threshold
+        .unwrap_or(options.adaptive_threshold);
+    options.subdivide = query.subdivide.unwrap_or(options.subdivide);
+    options.progressive = query.progressive.unwrap_or(options.progressive);
+    options.exponent = query.exponent.unwrap_or(options.exponent);
+    if let Some(name) = query.formula {
+        match Formula::from_name(&name) {
+            Some(formula) => options.formula = formula,
+            None => return Either::Left(format!("Error: unknown formula {}", name)),
+        }
+    }
+    //Either part of c switches to a Julia set, the missing part is 0
+    if query.julia_re.is_some() || query.julia_im.is_some() {
+        options.fractal =
+            Fractal::Julia(query.julia_re.unwrap_or(0.0), query.julia_im.unwrap_or(0.0));
+    }
+    if let Some(name) = query.palette {
+        match Palette::builtin(&name) {
+            Some(palette) => options.palette = palette,
+            None => return Either::Left(format!("Error: unknown palette {}", name)),
+        }
+    }
+    //Coefficients are given comma separated, highest power first
+    if let Some(newton) = query.newton {
+        let coefficients: Result<Vec<f64>, _> = newton.split(',').map(str::parse).collect();
+        let relaxation = query.relaxation.unwrap_or(newton::DEFAULT_RELAXATION);
+        let nova = query.nova.unwrap_or(false);
+        options.newton = coefficients
+            .ok()
+            .and_then(|coefficients| Newton::new(&coefficients, relaxation, nova));
+        if options.newton.is_none() {
+            return Either::Left(format!("Error: invalid polynomial {}", newton));
+        }
+    }
+    //Textures would have to be read from the server so only the shapes are offered here
+    if let Some(name) = query.trap {
+        options.trap = Trap::from_name(
+            &name,
+            query.trap_re.unwrap_or(0.0),
+            query.trap_im.unwrap_or(0.0),
+            query.trap_angle.unwrap_or(trap::DEFAULT_TRAP_ANGLE),
+            query.trap_size.unwrap_or(trap::DEFAULT_TRAP_SIZE),
+        );
+        if options.trap.is_none() {
+            return Either::Left(format!("Error: unknown trap {}", name));
+        }
+        if options.newton.is_some() {
+            return Either::Left(String::from(
+                "Error: orbit traps can't be used with Newton's method",
+            ));
+        }
+    }
+    if let Some(name) = query.average {
+        options.average = Average::from_name(
+            &name,
+            query
+                .stripe_density
+                .unwrap_or(average::DEFAULT_STRIPE_DENSITY),
+        );
+        if options.average.is_none() {
+            return Either::Left(format!("Error: unknown average {}", name));
+        }
+        if options.newton.is_some() {
+            return Either::Left(String::from(
+                "Error: average colouring can't be used with Newton's method",
+            ));
+        }
+    }
+    if query.light.unwrap_or(false) {
+        options.lighting = Some(Lighting {
+            azimuth: query
+                .light_azimuth
+                .unwrap_or(lighting::DEFAULT_LIGHT_AZIMUTH),
+            elevation: query
+                .light_elevation
+                .unwrap_or(lighting::DEFAULT_LIGHT_ELEVATION),
+            ambient: query
+                .light_ambient
+                .unwrap_or(lighting::DEFAULT_LIGHT_AMBIENT),
+            depth: query.light_depth.unwrap_or(lighting::DEFAULT_LIGHT_DEPTH),
+        });
+    }
+    if let Err(e) = check_exponent(&options)
+        .and_then(|_| check_bailout(&options))
+        .and_then(|_| check_scale(&options))
+        .and_then(|_| check_buddhabrot(&options))
+    {
+        return Either::Left(format!("Error: {}", e));
+    }
+    options.palette.offset = query.offset.unwrap_or(options.palette.offset);
+    options.palette.repeat = query.repeat.unwrap_or(options.palette.repeat);
+    options.palette.reverse = query.reverse.unwrap_or(options.palette.reverse);
 
     if Path::new(&filename).exists() {
-        return filename;
+        return Either::Left(filename);
+    }
+
+    //Rendering happens off the async runtime so other requests keep being served. If the client
+    //goes away Rocket drops this handler while it waits, and the guard with it, stopping the render
+    if !options.progressive {
+        let _guard = CancelOnDrop(options.cancel.clone());
+        let render = task::spawn_blocking(move || {
+            let mut field = IterationField::new(&options);
+            generate(&options, &mut field, |_, _| {})?;
+            write_image(&options, &field, &filename);
+            Ok(filename)
+        });
+        return Either::Left(match render.await {
+            Ok(Ok(filename)) => filename,
+            Ok(Err(Cancelled)) => format!("Error: {}", Cancelled),
+            Err(error) => format!("Error: render failed, {}", error),
+        });
     }
 
-    let mut buffer = vec![0; (options.width * options.height) as usize];
-    generate(options, &mut buffer);
-    let mut img: RgbImage = ImageBuffer::new(options.width, options.height);
+    //Stream the filename of each preview as soon as it is written, one per line, ending with
+    //the finished image. Rocket only notices a client has gone when writing to it, so when a
+    //preview can't be sent the stream is dropped along with the guard, stopping the render
+    let guard = CancelOnDrop(options.cancel.clone());
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    task::spawn_blocking(move || {
+        let mut field = IterationField::new(&options);
+        let result = generate(&options, &mut field, |step, preview| {
+            let preview_filename = preview_filename(&filename, step);
+            write_image(&options, preview, &preview_filename);
+            let _ = tx.send(preview_filename);
+        });
+        if result.is_ok() {
+            write_image(&options, &field, &filename);
+            let _ = tx.send(filename);
+        }
+    });
+
+    Either::Right(TextStream! {
+        let _guard = guard;
+        while let Some(filename) = rx.recv().await {
+            yield filename + "\n";
+        }
+    })
+}
+
+//Cancels a render when dropped, for tying a render to the lifetime of a response
+struct CancelOnDrop(CancelToken);
 
-    for (x, y, pixel) in img.enumerate_pixels_mut() {
-        //32 bit number but only storing rgb so split it into its 3 8 bit components
-        let b =
-            ((buffer[y as usize * options.width as usize + x as usize] & 0x00ff0000) >> 16) as u8;
-        let g =
-            ((buffer[y as usize * options.width as usize + x as usize] & 0x0000ff00) >> 8) as u8;
-        let r = (buffer[y as usize * options.width as usize + x as usize] & 0x000000ff) as u8;
-        *pixel = image::Rgb([r, g, b]);
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
     }
+}
+
+//Colour a field and save it, reporting rather than failing if it can't be written
+fn write_image(options: &Options, field: &IterationField, filename: &str) {
+    let img = mandelbrot::field_to_image(options, field);
 
-    img.save(&filename).unwrap_or_else(|_| {
+    img.save(filename).unwrap_or_else(|_| {
         eprintln!("Error: Could not write file");
     });
+}
+
+//argparse stops a List option at anything starting with -, so a negative number after --julia
+//or --newton is read as an option. Rewrite --julia re im as --julia=re --julia=im, taking up to
+//the given number of values for each option
+fn numeric_list_args(args: Vec<String>, lists: &[(&str, usize)]) -> Vec<String> {
+    let mut rewritten = Vec::with_capacity(args.len());
+    let mut args = args.into_iter().peekable();
+    while let Some(arg) = args.next() {
+        let mut values = Vec::new();
+        if let Some(&(option, limit)) = lists.iter().find(|(option, _)| *option == arg) {
+            while values.len() < limit {
+                match args.next_if(|value| value.parse::<f64>().is_ok()) {
+                    Some(value) => values.push(format!("{}={}", option, value)),
+                    None => break,
+                }
+            }
+        }
+        //Left alone without any numbers so argparse reports the missing value
+        if values.is_empty() {
+            rewritten.push(arg);
+        } else {
+            rewritten.append(&mut values);
+        }
+    }
+    rewritten
+}
 
-    filename
+//Filename for a progressive preview, output.png with a spacing of 16 gives output-preview16.png
+fn preview_filename(filename: &str, step: u32) -> String {
+    let path = Path::new(filename);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{}-preview{}.{}", stem, step, extension.to_string_lossy()),
+        None => format!("{}-preview{}", stem, step),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
 }
 
 #[rocket::main]
 async fn main() -> Result<(), rocket::Error> {
     let mut filename = std::string::String::from(DEFAULT_FILENAME);
+    let mut distance_filename = std::string::String::new();
+    let mut validate_subdivision = false;
+    let mut julia: Vec<f64> = Vec::new();
+    let mut coefficients: Vec<f64> = Vec::new();
+    let mut nova = false;
+    let mut relaxation = newton::DEFAULT_RELAXATION;
+    let mut buddhabrot = false;
+    let mut nebulabrot = false;
+    let mut windows: Vec<u32> = Vec::new();
+    let mut random = false;
+    let mut trap_name = String::new();
+    let mut trap_centre: Vec<f64> = Vec::new();
+    let mut trap_angle = trap::DEFAULT_TRAP_ANGLE;
+    let mut trap_size = trap::DEFAULT_TRAP_SIZE;
+    let mut trap_texture = String::new();
+    let mut average_name = String::new();
+    let mut stripe_density = average::DEFAULT_STRIPE_DENSITY;
+    let mut light = false;
+    let mut bounds: Vec<Decimal> = Vec::new();
+    let mut lighting = Lighting::default();
 
     let mut options = Options::default();
+    let mut palette_name = options.palette.name.clone();
+    let mut formula_name = options.formula.name().to_string();
 
     //Handle command line arguments
     {
@@ -186,8 +639,25 @@ async fn main() -> Result<(), rocket::Error> {
             options.max_iter
         );
         let scaley_text = format!("Set scale(default {})", options.scaley);
+        let rotation_text = format!(
+            "Turn the image anticlockwise about the centre by this many degrees (default {})",
+            options.rotation
+        );
         let samples_text = format!("Set samples for supersampling(default {})", options.samples);
-        let colour_text = format!("Set colour for image(default {})", options.colour);
+        let palette_text = format!(
+            "Set palette for image, one of {} or a .map, .ggr, .csv or .json gradient file (default {})",
+            palette::BUILTIN_PALETTES.join(", "),
+            options.palette.name
+        );
+        let offset_text = format!(
+            "Shift the start of the palette (default {})",
+            options.palette.offset
+        );
+        let repeat_text = format!(
+            "Number of times the palette repeats over the iteration range (default {})",
+            options.palette.repeat
+        );
+        let reverse_text = format!("Reverse the palette (default {})", options.palette.reverse);
         let progress_text = format!("Display progress bar (default {})", options.progress);
         let ocl_text = format!("Use opencl instead of cpu (default {})", options.ocl);
         let vulkan_text = format!("Use vulkan instead of cpu (default {})", options.vulkan);
@@ -195,6 +665,109 @@ async fn main() -> Result<(), rocket::Error> {
             "Split vulkan compute shader into chunks (default {})",
             options.vulkan_chunks
         );
+        let tile_size_text = format!(
+            "Size in pixels of the tiles handed out to cpu threads (default {})",
+            options.tile_size
+        );
+        let smooth_text = format!(
+            "Use smooth (normalized iteration count) colouring (default {})",
+            options.smooth
+        );
+        let bailout_text = format!(
+            "Set escape radius, at least 2, use a larger value with --smooth (default {})",
+            options.bailout
+        );
+        let histogram_text = format!(
+            "Use histogram equalised colouring (default {})",
+            options.histogram
+        );
+        let distance_text = format!(
+            "Colour by exterior distance estimate to draw sharp boundary filaments (default {})",
+            options.distance
+        );
+        let distance_field_text = format!(
+            "Also write the distance field as a 16 bit greyscale image, {} pixels or more is white (needs --distance)",
+            DISTANCE_FIELD_RANGE
+        );
+        let interior_check_text = format!(
+            "Skip the main cardioid and bulb and detect periodic orbits (default {})",
+            options.interior_check
+        );
+        let progressive_text = format!(
+            "Render coarse previews first and write each one next to the output (default {})",
+            options.progressive
+        );
+        let formula_text = format!(
+            "Set the variant of z^2 + c to iterate, one of {} (default {})",
+            FORMULAS.join(", "),
+            options.formula.name()
+        );
+        let trap_text = format!(
+            "Colour by how close orbits come to a trap instead, one of {}",
+            TRAPS.join(", ")
+        );
+        let trap_angle_text = format!("Angle of a line trap in degrees (default {})", trap_angle);
+        let trap_size_text = format!(
+            "Radius of a circle trap or side of a texture trap (default {})",
+            trap_size
+        );
+        let average_text = format!(
+            "Colour the outside by an average over the orbit, one of {}, works best with a large --bailout",
+            AVERAGES.join(", ")
+        );
+        let stripe_density_text = format!(
+            "Number of stripes for --average stripe (default {})",
+            stripe_density
+        );
+        let light_text = format!(
+            "Light the outside as a surface, its height is the distance estimate with --distance or the smooth iteration count (default {})",
+            light
+        );
+        let light_azimuth_text = format!(
+            "Direction of the light in degrees anticlockwise from the right (default {})",
+            lighting.azimuth
+        );
+        let light_elevation_text = format!(
+            "Height of the light in degrees, 90 is straight above (default {})",
+            lighting.elevation
+        );
+        let light_ambient_text = format!(
+            "Share of the light every pixel gets however it faces (default {})",
+            lighting.ambient
+        );
+        let light_depth_text = format!(
+            "Scale of the height, higher makes slopes steeper (default {})",
+            lighting.depth
+        );
+        let relaxation_text = format!("Scale each Newton step by this (default {})", relaxation);
+        let exponent_text = format!(
+            "Iterate z^exponent + c, above 1 or below -1. Non integer and negative exponents use polar form, negative ones can't be used with --smooth, --distance or --light (default {})",
+            options.exponent
+        );
+        let subdivide_text = format!(
+            "Fill rectangles whose border has a single iteration count without iterating them (default {})",
+            options.subdivide
+        );
+        let perturbation_text = format!(
+            "Force perturbation, it is used automatically past what double-double can resolve (default {})",
+            options.perturbation
+        );
+        let double_double_text = format!(
+            "Force the double-double kernel, it is used automatically past what f64 can resolve (default {})",
+            options.double_double
+        );
+        let adaptive_text = format!(
+            "Only use the full samples on pixels that differ from a neighbour (default {})",
+            options.adaptive
+        );
+        let adaptive_threshold_text = format!(
+            "Difference in smoothed iterations that marks a pixel for adaptive supersampling (default {})",
+            options.adaptive_threshold
+        );
+        let simd_text = format!(
+            "Disable the vectorised cpu kernel and iterate one sample at a time (simd default {})",
+            options.simd
+        );
         let service_text = format!("Run as a REST service (default {})", options.service);
         let threads_text = format!(
             "Set number of threads to use for processing(default {})",
@@ -227,6 +800,14 @@ async fn main() -> Result<(), rocket::Error> {
         parser
             .refer(&mut options.scaley)
             .add_option(&["--scale"], Store, &scaley_text);
+        parser
+            .refer(&mut options.rotation)
+            .add_option(&["--rotation"], Store, &rotation_text);
+        parser.refer(&mut bounds).add_option(
+            &["--bounds"],
+            List,
+            "Set the view from its corners as xmin xmax ymin ymax instead of the centre and scale",
+        );
         parser
             .refer(&mut options.samples)
             .add_option(&["--samples"], Store, &samples_text);
@@ -236,8 +817,26 @@ async fn main() -> Result<(), rocket::Error> {
             &vulkan_chunks_text,
         );
         parser
-            .refer(&mut options.colour)
-            .add_option(&["--colour"], Store, &colour_text);
+            .refer(&mut options.tile_size)
+            .add_option(&["--tile-size"], Store, &tile_size_text);
+        parser
+            .refer(&mut palette_name)
+            .add_option(&["--palette"], Store, &palette_text);
+        parser.refer(&mut options.palette.offset).add_option(
+            &["--palette-offset"],
+            Store,
+            &offset_text,
+        );
+        parser.refer(&mut options.palette.repeat).add_option(
+            &["--palette-repeat"],
+            Store,
+            &repeat_text,
+        );
+        parser.refer(&mut options.palette.reverse).add_option(
+            &["--palette-reverse"],
+            StoreTrue,
+            &reverse_text,
+        );
         parser
             .refer(&mut options.threads)
             .add_option(&["--threads", "-j"], Store, &threads_text);
@@ -258,11 +857,325 @@ async fn main() -> Result<(), rocket::Error> {
         parser
             .refer(&mut options.vulkan)
             .add_option(&["--vulkan"], StoreTrue, &vulkan_text);
+        parser
+            .refer(&mut options.smooth)
+            .add_option(&["--smooth"], StoreTrue, &smooth_text);
+        parser
+            .refer(&mut options.bailout)
+            .add_option(&["--bailout"], Store, &bailout_text);
+        parser.refer(&mut options.histogram).add_option(
+            &["--histogram"],
+            StoreTrue,
+            &histogram_text,
+        );
+        parser
+            .refer(&mut options.distance)
+            .add_option(&["--distance"], StoreTrue, &distance_text);
+        parser.refer(&mut distance_filename).add_option(
+            &["--distance-field"],
+            Store,
+            &distance_field_text,
+        );
+        parser.refer(&mut options.interior_check).add_option(
+            &["--interior-check"],
+            StoreTrue,
+            &interior_check_text,
+        );
+        parser.refer(&mut options.progressive).add_option(
+            &["--progressive"],
+            StoreTrue,
+            &progressive_text,
+        );
+        parser.refer(&mut options.subdivide).add_option(
+            &["--subdivide"],
+            StoreTrue,
+            &subdivide_text,
+        );
+        parser
+            .refer(&mut formula_name)
+            .add_option(&["--formula"], Store, &formula_text);
+        parser
+            .refer(&mut options.exponent)
+            .add_option(&["--exponent"], Store, &exponent_text);
+        parser.refer(&mut julia).add_option(
+            &["--julia"],
+            List,
+            "Render the Julia set for c = re + im i instead of the Mandelbrot set, takes re im",
+        );
+        parser.refer(&mut coefficients).add_option(
+            &["--newton"],
+            List,
+            "Render Newton's method on the polynomial with these coefficients, highest power first",
+        );
+        parser.refer(&mut nova).add_option(
+            &["--nova"],
+            StoreTrue,
+            "Add c each Newton step, c is the pixel or the --julia c (needs --newton)",
+        );
+        parser
+            .refer(&mut relaxation)
+            .add_option(&["--relaxation"], Store, &relaxation_text);
+        parser.refer(&mut buddhabrot).add_option(
+            &["--buddhabrot"],
+            StoreTrue,
+            "Render the density of escaping orbits instead, samples sets how many c per pixel",
+        );
+        parser.refer(&mut nebulabrot).add_option(
+            &["--nebulabrot"],
+            StoreTrue,
+            "Buddhabrot with orbits up to iterations, a tenth and a hundredth of that in red, green and blue",
+        );
+        parser.refer(&mut windows).add_option(
+            &["--windows"],
+            List,
+            "Iteration window for --buddhabrot as min max, or one for each of red, green and blue",
+        );
+        parser.refer(&mut random).add_option(
+            &["--random"],
+            StoreTrue,
+            "Pick c at random for --buddhabrot instead of from a grid",
+        );
+        parser
+            .refer(&mut trap_name)
+            .add_option(&["--trap"], Store, &trap_text);
+        parser.refer(&mut trap_centre).add_option(
+            &["--trap-centre"],
+            List,
+            "Centre of the trap as re im (default 0 0)",
+        );
+        parser
+            .refer(&mut trap_angle)
+            .add_option(&["--trap-angle"], Store, &trap_angle_text);
+        parser
+            .refer(&mut trap_size)
+            .add_option(&["--trap-size"], Store, &trap_size_text);
+        parser.refer(&mut trap_texture).add_option(
+            &["--trap-texture"],
+            Store,
+            "Image for a texture trap, implies --trap texture",
+        );
+        parser
+            .refer(&mut average_name)
+            .add_option(&["--average"], Store, &average_text);
+        parser.refer(&mut stripe_density).add_option(
+            &["--stripe-density"],
+            Store,
+            &stripe_density_text,
+        );
+        parser
+            .refer(&mut light)
+            .add_option(&["--light"], StoreTrue, &light_text);
+        parser.refer(&mut lighting.azimuth).add_option(
+            &["--light-azimuth"],
+            Store,
+            &light_azimuth_text,
+        );
+        parser.refer(&mut lighting.elevation).add_option(
+            &["--light-elevation"],
+            Store,
+            &light_elevation_text,
+        );
+        parser.refer(&mut lighting.ambient).add_option(
+            &["--light-ambient"],
+            Store,
+            &light_ambient_text,
+        );
+        parser
+            .refer(&mut lighting.depth)
+            .add_option(&["--light-depth"], Store, &light_depth_text);
+        parser.refer(&mut validate_subdivision).add_option(
+            &["--validate-subdivision"],
+            StoreTrue,
+            "Also render without subdivision and report the pixels that differ",
+        );
+        parser.refer(&mut options.perturbation).add_option(
+            &["--perturbation"],
+            StoreTrue,
+            &perturbation_text,
+        );
+        parser.refer(&mut options.double_double).add_option(
+            &["--double-double"],
+            StoreTrue,
+            &double_double_text,
+        );
+        parser
+            .refer(&mut options.adaptive)
+            .add_option(&["--adaptive"], StoreTrue, &adaptive_text);
+        parser.refer(&mut options.adaptive_threshold).add_option(
+            &["--adaptive-threshold"],
+            Store,
+            &adaptive_threshold_text,
+        );
+        parser
+            .refer(&mut options.simd)
+            .add_option(&["--no-simd"], StoreFalse, &simd_text);
         parser
             .refer(&mut options.service)
             .add_option(&["--service"], StoreTrue, &service_text);
 
-        parser.parse_args_or_exit();
+        let lists = [
+            ("--julia", 2),
+            ("--newton", usize::MAX),
+            ("--trap-centre", 2),
+            ("--bounds", 4),
+        ];
+        let args = numeric_list_args(std::env::args().collect(), &lists);
+        if let Err(code) = parser.parse(args, &mut std::io::stdout(), &mut std::io::stderr()) {
+            std::process::exit(code);
+        }
+    }
+
+    match &bounds[..] {
+        [] => {}
+        [xmin, xmax, ymin, ymax] if xmin < xmax && ymin < ymax => {
+            options.set_bounds(xmin, xmax, ymin, ymax)
+        }
+        [_, _, _, _] => {
+            eprintln!("Error: --bounds needs xmin and ymin to be less than xmax and ymax");
+            std::process::exit(2);
+        }
+        _ => {
+            eprintln!("Error: --bounds takes xmin xmax ymin ymax");
+            std::process::exit(2);
+        }
+    }
+
+    match Formula::from_name(&formula_name) {
+        Some(formula) => options.formula = formula,
+        None => {
+            eprintln!(
+                "Error: unknown formula {}, expected one of {}",
+                formula_name,
+                FORMULAS.join(", ")
+            );
+            std::process::exit(1);
+        }
+    }
+
+    match julia[..] {
+        [] => options.fractal = Fractal::Mandelbrot,
+        [re, im] => options.fractal = Fractal::Julia(re, im),
+        _ => {
+            eprintln!("Error: --julia takes the real and imaginary parts of c");
+            std::process::exit(2);
+        }
+    }
+
+    if !coefficients.is_empty() {
+        options.newton = Newton::new(&coefficients, relaxation, nova);
+        if options.newton.is_none() {
+            eprintln!("Error: --newton needs a coefficient that isn't 0");
+            std::process::exit(2);
+        }
+    } else if nova {
+        eprintln!("Error: --nova needs the polynomial from --newton");
+        std::process::exit(2);
+    }
+
+    if windows
+        .chunks(2)
+        .any(|window| window.len() == 2 && window[0] > window[1])
+    {
+        eprintln!("Error: --windows needs each min to be no more than its max");
+        std::process::exit(2);
+    }
+    if buddhabrot || nebulabrot || !windows.is_empty() {
+        options.buddhabrot = match windows[..] {
+            [] if nebulabrot => Some(Buddhabrot::nebulabrot(options.max_iter, random)),
+            [] => Some(Buddhabrot::new(1, options.max_iter, random)),
+            [min, max] => Some(Buddhabrot::new(min, max, random)),
+            [rmin, rmax, gmin, gmax, bmin, bmax] => Some(Buddhabrot {
+                windows: [(rmin, rmax), (gmin, gmax), (bmin, bmax)],
+                random,
+            }),
+            _ => {
+                eprintln!("Error: --windows takes min max for one window or for each of red, green and blue");
+                std::process::exit(2);
+            }
+        };
+    }
+
+    let (trap_x, trap_y) = match trap_centre[..] {
+        [] => (0.0, 0.0),
+        [x, y] => (x, y),
+        _ => {
+            eprintln!("Error: --trap-centre takes the real and imaginary parts of the centre");
+            std::process::exit(2);
+        }
+    };
+    if !trap_texture.is_empty() && (trap_name.is_empty() || trap_name == "texture") {
+        match Trap::texture(&trap_texture, trap_x, trap_y, trap_size) {
+            Ok(trap) => options.trap = Some(trap),
+            Err(e) => {
+                eprintln!("Error: could not read {}: {}", trap_texture, e);
+                std::process::exit(1);
+            }
+        }
+    } else if trap_name == "texture" {
+        eprintln!("Error: --trap texture needs an image from --trap-texture");
+        std::process::exit(2);
+    } else if !trap_name.is_empty() {
+        options.trap = Trap::from_name(&trap_name, trap_x, trap_y, trap_angle, trap_size);
+        if options.trap.is_none() {
+            eprintln!(
+                "Error: unknown trap {}, expected one of {}",
+                trap_name,
+                TRAPS.join(", ")
+            );
+            std::process::exit(1);
+        }
+    }
+    if options.trap.is_some() && (options.newton.is_some() || options.buddhabrot.is_some()) {
+        eprintln!("Error: orbit traps only work with escape time fractals");
+        std::process::exit(2);
+    }
+
+    if !average_name.is_empty() {
+        options.average = Average::from_name(&average_name, stripe_density);
+        if options.average.is_none() {
+            eprintln!(
+                "Error: unknown average {}, expected one of {}",
+                average_name,
+                AVERAGES.join(", ")
+            );
+            std::process::exit(1);
+        }
+        if options.newton.is_some() || options.buddhabrot.is_some() {
+            eprintln!("Error: average colouring only works with escape time fractals");
+            std::process::exit(2);
+        }
+    }
+
+    if light {
+        if options.buddhabrot.is_some() {
+            eprintln!("Error: --light only works with escape time fractals");
+            std::process::exit(2);
+        }
+        options.lighting = Some(lighting);
+    }
+
+    if let Err(e) = check_exponent(&options)
+        .and_then(|_| check_bailout(&options))
+        .and_then(|_| check_scale(&options))
+        .and_then(|_| check_buddhabrot(&options))
+    {
+        eprintln!("Error: {}", e);
+        std::process::exit(2);
+    }
+
+    match Palette::find(&palette_name) {
+        Ok(palette) => {
+            options.palette = Palette {
+                offset: options.palette.offset,
+                repeat: options.palette.repeat,
+                reverse: options.palette.reverse,
+                ..palette
+            }
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
     }
 
     if options.service {
@@ -276,26 +1189,58 @@ async fn main() -> Result<(), rocket::Error> {
             )
             .launch()
             .await?;
+    } else if let Some(buddhabrot) = &options.buddhabrot {
+        match generate_buddhabrot(&options, buddhabrot) {
+            Ok(img) => img.save(&filename).unwrap_or_else(|_| {
+                eprintln!("Error: Could not write file");
+            }),
+            Err(error) => eprintln!("Error: {}", error),
+        }
     } else {
-        let mut buffer = vec![0; (options.width * options.height) as usize];
+        let mut field = IterationField::new(&options);
+
+        let result = generate(&options, &mut field, |step, preview| {
+            write_image(&options, preview, &preview_filename(&filename, step));
+        });
+        if let Err(error) = result {
+            eprintln!("Error: {}", error);
+            return Ok(());
+        }
 
-        generate(options, &mut buffer);
-        //Create a blank image to write to
-        let mut img: RgbImage = ImageBuffer::new(options.width, options.height);
+        if validate_subdivision {
+            let mut brute_options = options.clone();
+            brute_options.subdivide = false;
+            let mut brute = IterationField::new(&brute_options);
+            generate(&brute_options, &mut brute, |_, _| {}).unwrap();
 
-        for (x, y, pixel) in img.enumerate_pixels_mut() {
-            //32 bit number but only storing rgb so split it into its 3 8 bit components
-            let b = ((buffer[y as usize * options.width as usize + x as usize] & 0x00ff0000) >> 16)
-                as u8;
-            let g = ((buffer[y as usize * options.width as usize + x as usize] & 0x0000ff00) >> 8)
-                as u8;
-            let r = (buffer[y as usize * options.width as usize + x as usize] & 0x000000ff) as u8;
-            *pixel = image::Rgb([r, g, b]);
+            let differences = field
+                .data
+                .iter()
+                .zip(brute.data.iter())
+                .filter(|(a, b)| a.iter != b.iter || a.inside != b.inside)
+                .count();
+            println!(
+                "Subdivision differs from brute force in {} of {} pixels",
+                differences,
+                field.data.len()
+            );
         }
 
-        img.save(&filename).unwrap_or_else(|_| {
-            eprintln!("Error: Could not write file");
-        });
+        //Colour the raw iteration data into an image
+        write_image(&options, &field, &filename);
+
+        if !distance_filename.is_empty() {
+            if options.distance {
+                field
+                    .distance_image(DISTANCE_FIELD_RANGE)
+                    .save(&distance_filename)
+                    .unwrap_or_else(|_| {
+                        eprintln!("Error: Could not write distance field");
+                    });
+            } else {
+                eprintln!("Error: --distance-field needs --distance");
+            }
+        }
     }
     Ok(())
 }
@@ -0,0 +1,196 @@
+//Newton's method fractals. Each pixel runs z -> z - R * p(z) / p'(z) until the steps become
+//tiny and is coloured by the root it lands on, shaded by how long it took to get there. The
+//Nova variant adds c each step, with c the pixel (z starting at 1) or the Julia c (z the pixel)
+use crate::average::Sum;
+use crate::tiles::TileScheduler;
+use crate::trap::Closest;
+use crate::{render_tiles, Cancelled, Fractal, Options, Orbit, View};
+use std::fmt;
+
+pub const DEFAULT_RELAXATION: f64 = 1.0;
+
+//Orbits are converged once a step is smaller than this, squared
+const CONVERGED: f64 = 1e-18;
+//How close a converged orbit has to be to a root to count as having found it, squared
+const ROOT_TOLERANCE: f64 = 1e-12;
+//Converging in this many iterations darkens a root's colour by a factor of e
+const SHADE_ITERATIONS: f64 = 16.0;
+//Iterations of Durand-Kerner used to find the roots up front
+const ROOT_ITERATIONS: u32 = 500;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Newton {
+    //Real coefficients of p, highest power first
+    pub coefficients: Vec<f64>,
+    //R in z - R * p(z) / p'(z), 1 is plain Newton's method
+    pub relaxation: f64,
+    pub nova: bool,
+}
+
+impl Newton {
+    //Leading zeros are dropped so the degree is right, None if nothing is left
+    pub fn new(coefficients: &[f64], relaxation: f64, nova: bool) -> Option<Self> {
+        let first = coefficients.iter().position(|&a| a != 0.0)?;
+        Some(Self {
+            coefficients: coefficients[first..].to_vec(),
+            relaxation,
+            nova,
+        })
+    }
+
+    pub fn degree(&self) -> usize {
+        self.coefficients.len() - 1
+    }
+
+    //p(z) and p'(z) by Horner's method
+    fn evaluate(&self, z: (f64, f64)) -> ((f64, f64), (f64, f64)) {
+        let mut p = (0.0, 0.0);
+        let mut dp = (0.0, 0.0);
+        for &a in &self.coefficients {
+            dp = add(mul(dp, z), p);
+            p = add(mul(p, z), (a, 0.0));
+        }
+        (p, dp)
+    }
+
+    //All roots of p at once by Durand-Kerner, used to tell which one an orbit converged to
+    pub fn roots(&self) -> Vec<(f64, f64)> {
+        let leading = self.coefficients[0];
+        let mut roots: Vec<(f64, f64)> = Vec::with_capacity(self.degree());
+        let mut power = (1.0, 0.0);
+        for _ in 0..self.degree() {
+            roots.push(power);
+            power = mul(power, (0.4, 0.9));
+        }
+
+        for _ in 0..ROOT_ITERATIONS {
+            for i in 0..roots.len() {
+                let mut denominator = (leading, 0.0);
+                for j in (0..roots.len()).filter(|&j| j != i) {
+                    denominator = mul(denominator, sub(roots[i], roots[j]));
+                }
+                let (p, _) = self.evaluate(roots[i]);
+                roots[i] = sub(roots[i], div(p, denominator));
+            }
+        }
+
+        roots
+    }
+}
+
+impl fmt::Display for Newton {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:?} relaxation {}",
+            if self.nova { "Nova" } else { "Newton" },
+            self.coefficients,
+            self.relaxation
+        )
+    }
+}
+
+pub fn mandelbrot(
+    options: &Options,
+    newton: &Newton,
+    tiles: &TileScheduler,
+) -> Result<(), Cancelled> {
+    let view = View::new(options);
+    let roots = newton.roots();
+    render_tiles(options, tiles, |sx, sy| {
+        let pixel = view.point(sx, sy);
+        let (z, c) = match (newton.nova, options.fractal) {
+            (false, _) => (pixel, (0.0, 0.0)),
+            (true, Fractal::Mandelbrot) => ((1.0, 0.0), pixel),
+            (true, Fractal::Julia(cx, cy)) => (pixel, (cx, cy)),
+        };
+        iterate(options, newton, &roots, z, c)
+    })
+}
+
+//Run the orbit until it converges. The orbit's iter is max_iter + 1 if it never does, x and y
+//are where it ended up and dx and dy its last step, which is what the smoothing needs. root is
+//1 + the index of the root it converged to or 0 if it isn't near any of them
+fn iterate(
+    options: &Options,
+    newton: &Newton,
+    roots: &[(f64, f64)],
+    mut z: (f64, f64),
+    c: (f64, f64),
+) -> Orbit {
+    let relaxation = (newton.relaxation, 0.0);
+    let mut step = (0.0, 0.0);
+    let mut iter = 0;
+
+    while iter <= options.max_iter {
+        let (p, dp) = newton.evaluate(z);
+        let next = add(sub(z, mul(relaxation, div(p, dp))), c);
+        step = sub(next, z);
+        z = next;
+        iter += 1;
+
+        let size = step.0 * step.0 + step.1 * step.1;
+        if size < CONVERGED {
+            break;
+        }
+        //Landed on a zero of p', nothing comes back from there
+        if !size.is_finite() {
+            iter = options.max_iter + 1;
+            break;
+        }
+    }
+
+    let root = match roots.iter().position(|&root| {
+        let d = sub(z, root);
+        d.0 * d.0 + d.1 * d.1 < ROOT_TOLERANCE
+    }) {
+        Some(index) if iter <= options.max_iter => index as u32 + 1,
+        _ => 0,
+    };
+
+    Orbit {
+        iter,
+        x: z.0,
+        y: z.1,
+        dx: step.0,
+        dy: step.1,
+        root,
+        trap: Closest::default(),
+        average: Sum::default(),
+    }
+}
+
+//Continuous version of the iteration count from the squared size of the last step, the closer
+//it came to the convergence limit the closer this is to iter. Steps shrink quadratically near a
+//simple root so the log-log of the last one gives the fraction of an iteration left over
+#[inline(always)]
+pub fn smooth_iter(iter: u32, size: f64) -> f64 {
+    let size = size.max(f64::MIN_POSITIVE);
+    iter as f64 - (size.ln() / CONVERGED.ln()).log2().clamp(0.0, 1.0)
+}
+
+//Colour of the root a pixel converged to, faded out the longer it took
+pub fn shade(colour: [u8; 3], smooth: f64) -> [u8; 3] {
+    let brightness = (-smooth / SHADE_ITERATIONS).exp();
+    colour.map(|channel| (channel as f64 * brightness) as u8)
+}
+
+fn add(a: (f64, f64), b: (f64, f64)) -> (f64, f64) {
+    (a.0 + b.0, a.1 + b.1)
+}
+
+fn sub(a: (f64, f64), b: (f64, f64)) -> (f64, f64) {
+    (a.0 - b.0, a.1 - b.1)
+}
+
+fn mul(a: (f64, f64), b: (f64, f64)) -> (f64, f64) {
+    (a.0 * b.0 - a.1 * b.1, a.0 * b.1 + a.1 * b.0)
+}
+
+fn div(a: (f64, f64), b: (f64, f64)) -> (f64, f64) {
+    let denominator = b.0 * b.0 + b.1 * b.1;
+    (
+        (a.0 * b.0 + a.1 * b.1) / denominator,
+        (a.1 * b.0 - a.0 * b.1) / denominator,
+    )
+}
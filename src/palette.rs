@@ -0,0 +1,597 @@
+//Colour gradients used by the colouring step to turn escape data into colours
+use std::f64::consts::PI;
+use std::fs;
+use std::path::Path;
+
+//A single colour on a gradient, position is in the range 0..1
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ColourStop {
+    pub position: f64,
+    pub colour: [u8; 3],
+}
+
+impl ColourStop {
+    pub fn new(position: f64, colour: [u8; 3]) -> Self {
+        Self { position, colour }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Palette {
+    pub name: String,
+    pub stops: Vec<ColourStop>,
+    //Shift the start of the gradient, in fractions of a full gradient
+    pub offset: f64,
+    //Number of times the gradient is repeated over the iteration range
+    pub repeat: f64,
+    pub reverse: bool,
+}
+
+pub const DEFAULT_PALETTE: &str = "grey";
+pub const DEFAULT_OFFSET: f64 = 0.0;
+pub const DEFAULT_REPEAT: f64 = 1.0;
+pub const DEFAULT_REVERSE: bool = false;
+
+//The first seven match the old colour codes 1 to 7 so colourise keeps the same colours
+pub const BUILTIN_PALETTES: [&str; 10] = [
+    "red", "green", "yellow", "blue", "magenta", "cyan", "grey", "classic", "fire", "ocean",
+];
+
+impl Palette {
+    pub fn new(name: &str, mut stops: Vec<ColourStop>) -> Self {
+        stops.sort_by(|a, b| a.position.total_cmp(&b.position));
+        Self {
+            name: name.to_string(),
+            stops,
+            offset: DEFAULT_OFFSET,
+            repeat: DEFAULT_REPEAT,
+            reverse: DEFAULT_REVERSE,
+        }
+    }
+
+    pub fn builtin(name: &str) -> Option<Self> {
+        let ramp = |colour: [u8; 3]| {
+            vec![
+                ColourStop::new(0.0, [0, 0, 0]),
+                ColourStop::new(1.0, colour),
+            ]
+        };
+
+        let stops = match name {
+            "red" => ramp([255, 0, 0]),
+            "green" => ramp([0, 255, 0]),
+            "yellow" => ramp([255, 255, 0]),
+            "blue" => ramp([0, 0, 255]),
+            "magenta" => ramp([255, 0, 255]),
+            "cyan" => ramp([0, 255, 255]),
+            "grey" => ramp([255, 255, 255]),
+            "classic" => vec![
+                ColourStop::new(0.0, [0, 7, 100]),
+                ColourStop::new(0.16, [32, 107, 203]),
+                ColourStop::new(0.42, [237, 255, 255]),
+                ColourStop::new(0.6425, [255, 170, 0]),
+                ColourStop::new(0.8575, [0, 2, 0]),
+                ColourStop::new(1.0, [0, 7, 100]),
+            ],
+            "fire" => vec![
+                ColourStop::new(0.0, [0, 0, 0]),
+                ColourStop::new(0.3, [180, 0, 0]),
+                ColourStop::new(0.6, [255, 160, 0]),
+                ColourStop::new(1.0, [255, 255, 200]),
+            ],
+            "ocean" => vec![
+                ColourStop::new(0.0, [0, 0, 40]),
+                ColourStop::new(0.5, [0, 120, 200]),
+                ColourStop::new(1.0, [220, 255, 255]),
+            ],
+            _ => return None,
+        };
+
+        Some(Self::new(name, stops))
+    }
+
+    //Look up a builtin palette by name or load it from a gradient file
+    pub fn find(name: &str) -> Result<Self, String> {
+        match Self::builtin(name) {
+            Some(palette) => Ok(palette),
+            None if Path::new(name).exists() => Self::load(Path::new(name)),
+            None => Err(format!(
+                "unknown palette {}, expected one of {} or a gradient file",
+                name,
+                BUILTIN_PALETTES.join(", ")
+            )),
+        }
+    }
+
+    //Load a palette from a Fractint .map, GIMP .ggr, csv or json gradient file
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("")
+            .to_lowercase();
+
+        let stops = match extension.as_str() {
+            "map" => parse_map(&text),
+            "ggr" => parse_ggr(&text),
+            "csv" => parse_csv(&text),
+            "json" => parse_json(&text),
+            _ => Err(format!(
+                "unsupported palette format '{}', expected map, ggr, csv or json",
+                extension
+            )),
+        }
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        if stops.is_empty() {
+            return Err(format!("{}: palette has no colours", path.display()));
+        }
+
+        let name = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("custom");
+        Ok(Self::new(name, stops))
+    }
+
+    //Map a value in the range 0..1 of the iteration range onto the gradient applying
+    //the offset, repeat and reverse settings
+    pub fn position(&self, t: f64) -> f64 {
+        let t = (t * self.repeat + self.offset).rem_euclid(1.0);
+        if self.reverse {
+            1.0 - t
+        } else {
+            t
+        }
+    }
+
+    //Linearly interpolate the colour at a position on the gradient
+    pub fn colour_at(&self, position: f64) -> [u8; 3] {
+        let (first, last) = match (self.stops.first(), self.stops.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return [0, 0, 0],
+        };
+
+        if position <= first.position {
+            return first.colour;
+        }
+        if position >= last.position {
+            return last.colour;
+        }
+
+        for pair in self.stops.windows(2) {
+            let (a, b) = (&pair[0], &pair[1]);
+            if position <= b.position {
+                let span = b.position - a.position;
+                let t = if span > 0.0 {
+                    (position - a.position) / span
+                } else {
+                    0.0
+                };
+                let mut colour = [0; 3];
+                for (i, channel) in colour.iter_mut().enumerate() {
+                    *channel = (a.colour[i] as f64 + (b.colour[i] as f64 - a.colour[i] as f64) * t)
+                        .round() as u8;
+                }
+                return colour;
+            }
+        }
+
+        last.colour
+    }
+
+    pub fn sample(&self, t: f64) -> [u8; 3] {
+        self.colour_at(self.position(t))
+    }
+
+    //Precompute the gradient at size evenly spaced positions, size doesn't need to be a power of two
+    pub fn lut(&self, size: u32) -> Vec<[u8; 3]> {
+        (0..size)
+            .map(|i| self.colour_at(i as f64 / size as f64))
+            .collect()
+    }
+
+    //Look up the colour for a value in the range 0..1 of the iteration range in a table from lut
+    #[inline(always)]
+    pub fn lookup(&self, lut: &[[u8; 3]], t: f64) -> [u8; 3] {
+        let index = (self.position(t) * lut.len() as f64) as usize;
+        lut[index.min(lut.len() - 1)]
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self::builtin(DEFAULT_PALETTE).unwrap()
+    }
+}
+
+//Spread colours evenly over the gradient
+fn evenly_spaced(colours: Vec<[u8; 3]>) -> Vec<ColourStop> {
+    let last = colours.len().saturating_sub(1).max(1) as f64;
+    colours
+        .into_iter()
+        .enumerate()
+        .map(|(i, colour)| ColourStop::new(i as f64 / last, colour))
+        .collect()
+}
+
+fn parse_channel(text: &str) -> Result<u8, String> {
+    let value: f64 = text
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid colour channel '{}'", text.trim()))?;
+    Ok(value.round().clamp(0.0, 255.0) as u8)
+}
+
+fn parse_hex(text: &str) -> Result<[u8; 3], String> {
+    let hex = text.trim().trim_start_matches('#');
+    let value = u32::from_str_radix(hex, 16)
+        .ok()
+        .filter(|_| hex.len() == 6)
+        .ok_or_else(|| format!("invalid hex colour '{}'", text.trim()))?;
+    Ok([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+}
+
+//Fractint maps are one "r g b" line per colour, anything after the third number is a comment
+fn parse_map(text: &str) -> Result<Vec<ColourStop>, String> {
+    let mut colours = Vec::new();
+    for line in text.lines() {
+        let fields: Vec<&str> = line.split_whitespace().take(3).collect();
+        if fields.is_empty() {
+            continue;
+        }
+        if fields.len() < 3 {
+            return Err(format!("expected 3 colour channels in '{}'", line));
+        }
+        colours.push([
+            parse_channel(fields[0])?,
+            parse_channel(fields[1])?,
+            parse_channel(fields[2])?,
+        ]);
+    }
+    Ok(evenly_spaced(colours))
+}
+
+//Blending function for a GIMP gradient segment, pos and middle are relative to the segment
+fn ggr_blend(blend: u32, pos: f64, middle: f64) -> f64 {
+    let linear = if pos <= middle {
+        if middle > 0.0 {
+            0.5 * pos / middle
+        } else {
+            0.0
+        }
+    } else if middle < 1.0 {
+        0.5 + 0.5 * (pos - middle) / (1.0 - middle)
+    } else {
+        1.0
+    };
+
+    match blend {
+        1 => pos.powf(0.5f64.ln() / middle.max(1e-10).ln()),
+        2 => ((-PI / 2.0 + PI * linear).sin() + 1.0) / 2.0,
+        3 => (1.0 - (linear - 1.0) * (linear - 1.0)).sqrt(),
+        4 => 1.0 - (1.0 - linear * linear).sqrt(),
+        5 => {
+            if pos < middle {
+                0.0
+            } else {
+                1.0
+            }
+        }
+        _ => linear,
+    }
+}
+
+//GIMP gradients are made of segments with their own blending function, linear segments are
+//exact with stops at the ends and midpoint, the curved ones are sampled
+fn parse_ggr(text: &str) -> Result<Vec<ColourStop>, String> {
+    const SEGMENT_SAMPLES: usize = 16;
+
+    let mut lines = text.lines().map(str::trim).filter(|l| !l.is_empty());
+    if lines.next() != Some("GIMP Gradient") {
+        return Err("missing 'GIMP Gradient' header".to_string());
+    }
+
+    let mut line = lines.next().ok_or("missing segment count")?;
+    if line.starts_with("Name:") {
+        line = lines.next().ok_or("missing segment count")?;
+    }
+    let count: usize = line
+        .parse()
+        .map_err(|_| format!("invalid segment count '{}'", line))?;
+
+    let mut stops = Vec::new();
+    for _ in 0..count {
+        let line = lines
+            .next()
+            .ok_or("gradient has fewer segments than declared")?;
+        let values = line
+            .split_whitespace()
+            .map(|v| v.parse::<f64>())
+            .collect::<Result<Vec<f64>, _>>()
+            .map_err(|_| format!("invalid segment '{}'", line))?;
+        if values.len() < 11 {
+            return Err(format!("invalid segment '{}'", line));
+        }
+
+        let (left, mid, right) = (values[0], values[1], values[2]);
+        let start = [values[3], values[4], values[5]];
+        let end = [values[7], values[8], values[9]];
+        let blend = values.get(11).copied().unwrap_or(0.0) as u32;
+        let width = right - left;
+        let middle = if width > 0.0 {
+            (mid - left) / width
+        } else {
+            0.5
+        };
+
+        let colour = |f: f64| {
+            let mut colour = [0; 3];
+            for (i, channel) in colour.iter_mut().enumerate() {
+                *channel = ((start[i] + (end[i] - start[i]) * f) * 255.0)
+                    .round()
+                    .clamp(0.0, 255.0) as u8;
+            }
+            colour
+        };
+
+        if blend == 0 {
+            stops.push(ColourStop::new(left, colour(0.0)));
+            stops.push(ColourStop::new(mid, colour(0.5)));
+            stops.push(ColourStop::new(right, colour(1.0)));
+        } else {
+            for i in 0..=SEGMENT_SAMPLES {
+                let pos = i as f64 / SEGMENT_SAMPLES as f64;
+                stops.push(ColourStop::new(
+                    left + pos * width,
+                    colour(ggr_blend(blend, pos, middle)),
+                ));
+            }
+        }
+    }
+    Ok(stops)
+}
+
+//Csv lines are either "position,r,g,b", "position,#rrggbb", "r,g,b" or "#rrggbb", colours
+//without a position are spread evenly. Blank lines, header rows of text and lines starting with
+//# are skipped, apart from a line that is only a #rrggbb colour
+fn parse_csv(text: &str) -> Result<Vec<ColourStop>, String> {
+    let mut positioned = Vec::new();
+    let mut colours = Vec::new();
+
+    for line in text.lines() {
+        let line = line.trim();
+        if let Some(comment) = line.strip_prefix('#') {
+            if comment.len() != 6 || !comment.chars().all(|c| c.is_ascii_hexdigit()) {
+                continue;
+            }
+        }
+        if line.is_empty() {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let first = fields[0];
+        if !first.starts_with('#') && first.parse::<f64>().is_err() {
+            //Header row
+            continue;
+        }
+
+        match fields.len() {
+            1 => colours.push(parse_hex(first)?),
+            2 => positioned.push(ColourStop::new(
+                first.parse().unwrap(),
+                parse_hex(fields[1])?,
+            )),
+            3 => colours.push([
+                parse_channel(fields[0])?,
+                parse_channel(fields[1])?,
+                parse_channel(fields[2])?,
+            ]),
+            4 => positioned.push(ColourStop::new(
+                first.parse().unwrap(),
+                [
+                    parse_channel(fields[1])?,
+                    parse_channel(fields[2])?,
+                    parse_channel(fields[3])?,
+                ],
+            )),
+            _ => return Err(format!("invalid line '{}'", line)),
+        }
+    }
+
+    if !positioned.is_empty() && !colours.is_empty() {
+        return Err("mix of colours with and without positions".to_string());
+    }
+    if positioned.is_empty() {
+        Ok(evenly_spaced(colours))
+    } else {
+        Ok(positioned)
+    }
+}
+
+fn json_colour(value: &serde_json::Value) -> Result<[u8; 3], String> {
+    match value {
+        serde_json::Value::String(hex) => parse_hex(hex),
+        serde_json::Value::Array(channels) if channels.len() == 3 => {
+            let mut colour = [0; 3];
+            for (i, channel) in channels.iter().enumerate() {
+                let value = channel
+                    .as_f64()
+                    .ok_or_else(|| format!("invalid colour channel {}", channel))?;
+                colour[i] = value.round().clamp(0.0, 255.0) as u8;
+            }
+            Ok(colour)
+        }
+        _ => Err(format!("invalid colour {}", value)),
+    }
+}
+
+//Json palettes are a list of colours or of {"position": p, "colour": c} objects, optionally
+//wrapped in {"stops": [...]}. Colours are "#rrggbb" strings or [r, g, b] arrays
+fn parse_json(text: &str) -> Result<Vec<ColourStop>, String> {
+    let value: serde_json::Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    let list = match value.get("stops").unwrap_or(&value) {
+        serde_json::Value::Array(list) => list,
+        _ => return Err("expected a list of colour stops".to_string()),
+    };
+
+    if list.iter().all(|stop| stop.is_object()) {
+        list.iter()
+            .map(|stop| {
+                let position = stop
+                    .get("position")
+                    .and_then(|p| p.as_f64())
+                    .ok_or_else(|| format!("colour stop {} has no position", stop))?;
+                let colour = stop
+                    .get("colour")
+                    .or_else(|| stop.get("color"))
+                    .ok_or_else(|| format!("colour stop {} has no colour", stop))?;
+                Ok(ColourStop::new(position, json_colour(colour)?))
+            })
+            .collect()
+    } else {
+        let colours = list
+            .iter()
+            .map(json_colour)
+            .collect::<Result<Vec<[u8; 3]>, String>>()?;
+        Ok(evenly_spaced(colours))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_spreads_colours_and_ignores_comments() {
+        let stops = parse_map("0 0 0 black\n\n255 128 0\n255 255 255 white\n").unwrap();
+        assert_eq!(
+            stops,
+            vec![
+                ColourStop::new(0.0, [0, 0, 0]),
+                ColourStop::new(0.5, [255, 128, 0]),
+                ColourStop::new(1.0, [255, 255, 255]),
+            ]
+        );
+    }
+
+    #[test]
+    fn map_rejects_malformed_lines() {
+        assert!(parse_map("0 0 0\n255 255\n").is_err());
+        assert!(parse_map("0 0 zero\n").is_err());
+    }
+
+    #[test]
+    fn ggr_linear_segment_is_exact() {
+        let text = "GIMP Gradient\nName: Test\n1\n\
+                    0 0.5 1 0 0 0 1 1 1 1 1 0 0\n";
+        assert_eq!(
+            parse_ggr(text).unwrap(),
+            vec![
+                ColourStop::new(0.0, [0, 0, 0]),
+                ColourStop::new(0.5, [128, 128, 128]),
+                ColourStop::new(1.0, [255, 255, 255]),
+            ]
+        );
+    }
+
+    #[test]
+    fn ggr_curved_segment_is_sampled() {
+        //Sine blend, halfway through the segment is halfway between the colours
+        let stops = parse_ggr("GIMP Gradient\n1\n0 0.5 1 0 0 0 1 1 1 1 1 2 0\n").unwrap();
+        assert_eq!(stops.len(), 17);
+        assert_eq!(stops[0], ColourStop::new(0.0, [0, 0, 0]));
+        assert_eq!(stops[8], ColourStop::new(0.5, [128, 128, 128]));
+        assert_eq!(stops[16], ColourStop::new(1.0, [255, 255, 255]));
+    }
+
+    #[test]
+    fn ggr_rejects_malformed_files() {
+        assert!(parse_ggr("1\n0 0.5 1 0 0 0 1 1 1 1 1 0 0\n").is_err());
+        assert!(parse_ggr("GIMP Gradient\n2\n0 0.5 1 0 0 0 1 1 1 1 1 0 0\n").is_err());
+        assert!(parse_ggr("GIMP Gradient\n1\n0 0.5 1 0 0 0 1 1 1 1\n").is_err());
+        assert!(parse_ggr("GIMP Gradient\n1\n0 0.5 one 0 0 0 1 1 1 1 1 0 0\n").is_err());
+        assert!(parse_ggr("GIMP Gradient\nmany\n").is_err());
+    }
+
+    #[test]
+    fn csv_with_positions_skips_header_and_comments() {
+        let text = "position,red,green,blue\n\
+                    # my gradient\n\
+                    # black, orange, white\n\
+                    0,0,0,0\n\
+                    \n\
+                    0.25, #ff8000\n\
+                    1,255,255,255\n";
+        assert_eq!(
+            parse_csv(text).unwrap(),
+            vec![
+                ColourStop::new(0.0, [0, 0, 0]),
+                ColourStop::new(0.25, [255, 128, 0]),
+                ColourStop::new(1.0, [255, 255, 255]),
+            ]
+        );
+    }
+
+    #[test]
+    fn csv_without_positions_keeps_hex_lines() {
+        let text = "# my gradient\n#000000\n  #FF8000  \n255,255,255\n";
+        assert_eq!(
+            parse_csv(text).unwrap(),
+            vec![
+                ColourStop::new(0.0, [0, 0, 0]),
+                ColourStop::new(0.5, [255, 128, 0]),
+                ColourStop::new(1.0, [255, 255, 255]),
+            ]
+        );
+    }
+
+    #[test]
+    fn csv_rejects_malformed_lines() {
+        assert!(parse_csv("0,1,2,3,4\n").is_err());
+        assert!(parse_csv("0,red\n").is_err());
+        assert!(parse_csv("0,0,zero,0\n").is_err());
+        assert!(parse_csv("#000000\n1,#ffffff\n").is_err());
+    }
+
+    #[test]
+    fn json_list_of_colours() {
+        let stops = parse_json(r##"["#000000", [255, 128, 0], "#ffffff"]"##).unwrap();
+        assert_eq!(
+            stops,
+            vec![
+                ColourStop::new(0.0, [0, 0, 0]),
+                ColourStop::new(0.5, [255, 128, 0]),
+                ColourStop::new(1.0, [255, 255, 255]),
+            ]
+        );
+    }
+
+    #[test]
+    fn json_stops_with_positions() {
+        let text = r##"{"stops": [
+            {"position": 0, "colour": "#000000"},
+            {"position": 0.25, "color": [255, 128, 0]}
+        ]}"##;
+        assert_eq!(
+            parse_json(text).unwrap(),
+            vec![
+                ColourStop::new(0.0, [0, 0, 0]),
+                ColourStop::new(0.25, [255, 128, 0]),
+            ]
+        );
+    }
+
+    #[test]
+    fn json_rejects_malformed_palettes() {
+        assert!(parse_json("[\"#000000\"").is_err());
+        assert!(parse_json(r#"{"colours": []}"#).is_err());
+        assert!(parse_json(r##"[{"colour": "#000000"}]"##).is_err());
+        assert!(parse_json(r#"[{"position": 0}]"#).is_err());
+        assert!(parse_json(r##"["#00000g"]"##).is_err());
+        assert!(parse_json("[[0, 0]]").is_err());
+    }
+}
@@ -0,0 +1,158 @@
+//Perturbation rendering for deep zooms. One reference orbit is iterated at high precision at
+//the centre of the view and every pixel only iterates its (tiny) difference from it in f64,
+//which keeps working until the pixel spacing underflows f64 at around 1e-300
+use crate::average::Sum;
+use crate::tiles::TileScheduler;
+use crate::trap::Closest;
+use crate::{render_tiles, Cancelled, Options, Orbit, View};
+use dashu_float::round::mode::HalfAway;
+use dashu_float::FBig;
+
+pub type BigFloat = FBig<HalfAway, 2>;
+
+//Bits kept beyond what is needed to tell neighbouring pixels apart
+const GUARD_BITS: usize = 64;
+//Spacing of the smallest f64, the pixels can't be iterated any deeper than this anyway so a
+//spacing that underflows to 0 is capped here instead of asking for infinite precision
+const MAX_SPACING_BITS: f64 = 1074.0;
+
+//Reference orbit Z_0 = 0, Z_1 = C, ... rounded to f64, it stops early if the reference escapes
+pub struct ReferenceOrbit {
+    pub orbit: Vec<(f64, f64)>,
+}
+
+//Precision needed for the reference orbit so it still resolves a single subsample
+pub fn precision_bits(options: &Options) -> usize {
+    let spacing = options.spacing();
+    (-spacing.log2()).clamp(0.0, MAX_SPACING_BITS) as usize + GUARD_BITS
+}
+
+impl ReferenceOrbit {
+    pub fn new(options: &Options) -> Self {
+        let bits = precision_bits(options);
+        let cx = options
+            .centrex
+            .clone()
+            .with_base_and_precision::<2>(bits)
+            .value();
+        let cy = options
+            .centrey
+            .clone()
+            .with_base_and_precision::<2>(bits)
+            .value();
+        Self::from_centre(options, cx, cy)
+    }
+
+    pub fn from_centre(options: &Options, cx: BigFloat, cy: BigFloat) -> Self {
+        //Escaped reference orbits are rebased onto so stop well past the bailout radius
+        let escape2 = (options.bailout * options.bailout).max(256.0);
+        let mut orbit = vec![(0.0, 0.0)];
+        let mut x = cx.clone();
+        let mut y = cy.clone();
+
+        //Pixels can run to max_iter + 1 iterations from Z_1 so the orbit needs one more entry
+        while orbit.len() <= options.max_iter as usize + 2 {
+            let (fx, fy) = (x.to_f64().value(), y.to_f64().value());
+            orbit.push((fx, fy));
+            if fx * fx + fy * fy > escape2 {
+                break;
+            }
+
+            let xy = &x * &y;
+            let xtemp = &x * &x - &y * &y + &cx;
+            y = &xy + &xy + &cy;
+            x = xtemp;
+        }
+
+        Self { orbit }
+    }
+}
+
+//Iterate the difference dz between a pixel's orbit and the reference. Whenever the pixel's
+//orbit gets closer to 0 than dz, or the reference runs out, dz is rebased onto the start of
+//the reference orbit, which avoids the glitches of plain perturbation with one reference
+#[inline(always)]
+fn iterate(
+    options: &Options,
+    reference: &[(f64, f64)],
+    dcx: f64,
+    dcy: f64,
+    bailout2: f64,
+) -> Orbit {
+    let last = reference.len() - 1;
+    let mut iter: u32 = 0;
+    let mut m = 1;
+    let mut dzx = dcx;
+    let mut dzy = dcy;
+    let mut x = reference[m].0 + dzx;
+    let mut y = reference[m].1 + dzy;
+    let mut xtemp: f64;
+    let mut dx: f64 = 1.0;
+    let mut dy: f64 = 0.0;
+    let mut closest = Closest::default();
+    let mut sum = Sum::default();
+    //Z_1 is C so this is where the pixel is
+    let cmag = ((reference[1].0 + dcx).powi(2) + (reference[1].1 + dcy).powi(2)).sqrt();
+
+    while x * x + y * y < bailout2 && iter <= options.max_iter {
+        if m == last || x * x + y * y < dzx * dzx + dzy * dzy {
+            dzx = x;
+            dzy = y;
+            m = 0;
+        }
+        let power = x * x + y * y;
+
+        if options.distance {
+            //dz/dc = 2 * z * dz/dc + 1 on the full orbit which is only ever around bailout size
+            xtemp = 2.0 * (x * dx - y * dy) + 1.0;
+            dy = 2.0 * (x * dy + y * dx);
+            dx = xtemp;
+        }
+
+        //dz = (2 * Z + dz) * dz + dc
+        let (zx, zy) = reference[m];
+        let tx = 2.0 * zx + dzx;
+        let ty = 2.0 * zy + dzy;
+        xtemp = tx * dzx - ty * dzy + dcx;
+        dzy = tx * dzy + ty * dzx + dcy;
+        dzx = xtemp;
+        m += 1;
+        iter += 1;
+
+        x = reference[m].0 + dzx;
+        y = reference[m].1 + dzy;
+        if let Some(trap) = &options.trap {
+            closest.update(trap, x, y);
+        }
+        if let Some(average) = options.average {
+            sum.add(average, x, y, power, cmag);
+        }
+    }
+
+    Orbit {
+        iter,
+        x,
+        y,
+        dx,
+        dy,
+        root: 0,
+        trap: closest,
+        average: sum,
+    }
+}
+
+//Cpu kernel for perturbation rendering, the reference orbit is computed once and shared by
+//all of the threads. The interior check is ignored as it needs the absolute position
+pub fn mandelbrot(
+    options: &Options,
+    reference: &ReferenceOrbit,
+    tiles: &TileScheduler,
+) -> Result<(), Cancelled> {
+    let view = View::new(options);
+    let bailout2 = options.bailout * options.bailout;
+
+    render_tiles(options, tiles, |sx, sy| {
+        let (dcx, dcy) = view.offset(sx, sy);
+        iterate(options, &reference.orbit, dcx, dcy, bailout2)
+    })
+}
@@ -0,0 +1,66 @@
+//Progressive rendering for interactive use. The first pass renders every 16th pixel in both
+//directions and each pass after halves the spacing, only rendering the pixels that no earlier
+//pass did, until the last pass fills in the rest of the image
+use crate::perturbation::ReferenceOrbit;
+use crate::tiles::TileScheduler;
+use crate::{render_cpu, Cancelled, IterationField, Options};
+
+//Pixel spacing of the first pass, must be a power of two
+pub const FIRST_STEP: u32 = 16;
+
+//Render the field in passes, calling callback with the spacing and a preview of the image
+//after each one. The last call has a spacing of 1 and is the finished field, a cancelled pass
+//doesn't get a callback
+pub fn render<C>(
+    options: &Options,
+    field: &mut IterationField,
+    reference: Option<&ReferenceOrbit>,
+    mut callback: C,
+) -> Result<(), Cancelled>
+where
+    C: FnMut(u32, &IterationField),
+{
+    let mut step = FIRST_STEP;
+    loop {
+        let mask = pass_mask(field.width, field.height, step);
+        let tiles = TileScheduler::with_mask(field, options.tile_size, mask);
+        render_cpu(options, &tiles, reference, |_, _| {})?;
+        drop(tiles);
+
+        if step == 1 {
+            callback(step, field);
+            return Ok(());
+        }
+        callback(step, &preview(field, step));
+        step /= 2;
+    }
+}
+
+//Pixels on the grid with the given spacing that aren't on the grid of the pass before
+fn pass_mask(width: u32, height: u32, step: u32) -> Vec<bool> {
+    //is_multiple_of would need Rust 1.87, newer than anything else here
+    #[allow(unknown_lints, clippy::manual_is_multiple_of)]
+    let on_grid = |x: u32, y: u32, step: u32| x % step == 0 && y % step == 0;
+    (0..height)
+        .flat_map(|y| {
+            (0..width).map(move |x| {
+                on_grid(x, y, step) && (step == FIRST_STEP || !on_grid(x, y, step * 2))
+            })
+        })
+        .collect()
+}
+
+//Copy of the field where every pixel not rendered yet takes the value of the rendered pixel at
+//the top left of its block
+pub fn preview(field: &IterationField, step: u32) -> IterationField {
+    let mut preview = field.clone();
+    for y in 0..field.height {
+        for x in 0..field.width {
+            let index = (y * field.width + x) as usize;
+            let source = ((y - y % step) * field.width + (x - x % step)) as usize;
+            preview.data[index] = field.data[source];
+            preview.owner[index] = field.owner[source];
+        }
+    }
+    preview
+}
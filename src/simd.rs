@@ -0,0 +1,280 @@
+//Vectorised version of the f64 cpu kernel. LANES samples are iterated together with a mask for
+//the lanes that are already done, so the loop runs until the slowest lane escapes. AVX2 is
+//detected at runtime and everything else, including exponents other than 2, orbit traps and
+//average colouring, falls back to the scalar kernel
+use crate::average::Sum;
+use crate::trap::Closest;
+use crate::{in_main_bulbs, iterate, Fold, Options, Orbit};
+
+//Two AVX2 registers of four f64
+pub const LANES: usize = 8;
+
+pub(crate) fn iterate_lanes(
+    options: &Options,
+    x0: [f64; LANES],
+    y0: [f64; LANES],
+    bailout2: f64,
+) -> [Orbit; LANES] {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2")
+            && options.exponent == 2.0
+            && options.trap.is_none()
+            && options.average.is_none()
+        {
+            return unsafe { iterate_avx2(options, x0, y0, bailout2) };
+        }
+    }
+
+    let mut orbits = [Orbit::default(); LANES];
+    for (lane, orbit) in orbits.iter_mut().enumerate() {
+        *orbit = iterate(options, x0[lane], y0[lane], bailout2);
+    }
+    orbits
+}
+
+//Same steps as iterate, without fused multiply adds, so every lane matches the scalar kernel
+//exactly. The lanes are split over VECTORS registers which are stepped together to hide the
+//latency of each step. All of the active lanes have run the same number of iterations so the
+//Brent window for the periodicity check can be shared
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn iterate_avx2(
+    options: &Options,
+    x0: [f64; LANES],
+    y0: [f64; LANES],
+    bailout2: f64,
+) -> [Orbit; LANES] {
+    use crate::PERIODICITY_EPSILON;
+    use std::arch::x86_64::*;
+
+    const WIDTH: usize = 4;
+    const VECTORS: usize = LANES / WIDTH;
+
+    //Iteration counts are kept as f64 so they can share the comparison and blend instructions
+    let mut start = [0.0; LANES];
+    if options.interior_check && options.classic() {
+        for lane in 0..LANES {
+            if in_main_bulbs(x0[lane], y0[lane]) {
+                start[lane] = (options.max_iter + 1) as f64;
+            }
+        }
+    }
+
+    let mut x = [_mm256_setzero_pd(); VECTORS];
+    let mut y = [_mm256_setzero_pd(); VECTORS];
+    let mut iter = [_mm256_setzero_pd(); VECTORS];
+    for v in 0..VECTORS {
+        x[v] = _mm256_loadu_pd(x0[v * WIDTH..].as_ptr());
+        y[v] = _mm256_loadu_pd(y0[v * WIDTH..].as_ptr());
+        iter[v] = _mm256_loadu_pd(start[v * WIDTH..].as_ptr());
+    }
+
+    //Julia sets start at the pixel with a fixed c and no + 1 in the derivative
+    let (cx, cy, dc) = match options.julia() {
+        Some((re, im)) => (
+            [_mm256_set1_pd(re); VECTORS],
+            [_mm256_set1_pd(im); VECTORS],
+            _mm256_setzero_pd(),
+        ),
+        None => (x, y, _mm256_set1_pd(1.0)),
+    };
+    let mut dx = [_mm256_set1_pd(1.0); VECTORS];
+    let mut dy = [_mm256_setzero_pd(); VECTORS];
+
+    let one = _mm256_set1_pd(1.0);
+    let two = _mm256_set1_pd(2.0);
+    let max_iter = _mm256_set1_pd(options.max_iter as f64);
+    let inside = _mm256_set1_pd((options.max_iter + 1) as f64);
+    let bailout2 = _mm256_set1_pd(bailout2);
+    let epsilon = _mm256_set1_pd(PERIODICITY_EPSILON);
+    let sign = _mm256_set1_pd(-0.0);
+
+    //Sign bits to flip for each fold, xor with them folds z and flips dz the same way
+    #[target_feature(enable = "avx2")]
+    #[inline]
+    unsafe fn flips(fold: Fold, value: __m256d, sign: __m256d) -> __m256d {
+        match fold {
+            Fold::Keep => _mm256_setzero_pd(),
+            Fold::Abs => _mm256_and_pd(value, sign),
+            Fold::Negate => sign,
+        }
+    }
+    let (fold_x, fold_y) = options.formula.folds();
+    let abs_real = if options.formula.abs_real() {
+        Fold::Abs
+    } else {
+        Fold::Keep
+    };
+
+    let mut checkx = x;
+    let mut checky = y;
+    let mut period: u32 = 0;
+    let mut check_limit: u32 = 1;
+
+    loop {
+        let mut any = 0;
+        for v in 0..VECTORS {
+            let flipx = flips(fold_x, x[v], sign);
+            let flipy = flips(fold_y, y[v], sign);
+            let zx = _mm256_xor_pd(x[v], flipx);
+            let zy = _mm256_xor_pd(y[v], flipy);
+
+            let x2 = _mm256_mul_pd(zx, zx);
+            let y2 = _mm256_mul_pd(zy, zy);
+            let active = _mm256_and_pd(
+                _mm256_cmp_pd::<_CMP_LT_OQ>(_mm256_add_pd(x2, y2), bailout2),
+                _mm256_cmp_pd::<_CMP_LE_OQ>(iter[v], max_iter),
+            );
+            any |= _mm256_movemask_pd(active);
+
+            //Celtic and Buffalo take the abs of the real part
+            let real = _mm256_sub_pd(x2, y2);
+            let flipreal = flips(abs_real, real, sign);
+
+            if options.distance {
+                //dz = 2 * z * dz + 1
+                let zdx = _mm256_xor_pd(dx[v], flipx);
+                let zdy = _mm256_xor_pd(dy[v], flipy);
+                let xtemp = _mm256_add_pd(
+                    _mm256_xor_pd(
+                        _mm256_mul_pd(
+                            two,
+                            _mm256_sub_pd(_mm256_mul_pd(zx, zdx), _mm256_mul_pd(zy, zdy)),
+                        ),
+                        flipreal,
+                    ),
+                    dc,
+                );
+                let ytemp = _mm256_mul_pd(
+                    two,
+                    _mm256_add_pd(_mm256_mul_pd(zx, zdy), _mm256_mul_pd(zy, zdx)),
+                );
+                dx[v] = _mm256_blendv_pd(dx[v], xtemp, active);
+                dy[v] = _mm256_blendv_pd(dy[v], ytemp, active);
+            }
+
+            let xtemp = _mm256_add_pd(_mm256_xor_pd(real, flipreal), cx[v]);
+            let ytemp = _mm256_add_pd(_mm256_mul_pd(_mm256_mul_pd(two, zx), zy), cy[v]);
+            x[v] = _mm256_blendv_pd(x[v], xtemp, active);
+            y[v] = _mm256_blendv_pd(y[v], ytemp, active);
+            iter[v] = _mm256_add_pd(iter[v], _mm256_and_pd(active, one));
+
+            if options.interior_check {
+                let periodic = _mm256_and_pd(
+                    active,
+                    _mm256_and_pd(
+                        _mm256_cmp_pd::<_CMP_LT_OQ>(
+                            _mm256_andnot_pd(sign, _mm256_sub_pd(x[v], checkx[v])),
+                            epsilon,
+                        ),
+                        _mm256_cmp_pd::<_CMP_LT_OQ>(
+                            _mm256_andnot_pd(sign, _mm256_sub_pd(y[v], checky[v])),
+                            epsilon,
+                        ),
+                    ),
+                );
+                iter[v] = _mm256_blendv_pd(iter[v], inside, periodic);
+            }
+        }
+
+        //The step above is a no-op for lanes that were already done
+        if any == 0 {
+            break;
+        }
+
+        if options.interior_check {
+            period += 1;
+            if period == check_limit {
+                period = 0;
+                check_limit = check_limit.saturating_mul(2);
+                checkx = x;
+                checky = y;
+            }
+        }
+    }
+
+    let mut iters = [0.0; LANES];
+    let mut xs = [0.0; LANES];
+    let mut ys = [0.0; LANES];
+    let mut dxs = [0.0; LANES];
+    let mut dys = [0.0; LANES];
+    for v in 0..VECTORS {
+        _mm256_storeu_pd(iters[v * WIDTH..].as_mut_ptr(), iter[v]);
+        _mm256_storeu_pd(xs[v * WIDTH..].as_mut_ptr(), x[v]);
+        _mm256_storeu_pd(ys[v * WIDTH..].as_mut_ptr(), y[v]);
+        _mm256_storeu_pd(dxs[v * WIDTH..].as_mut_ptr(), dx[v]);
+        _mm256_storeu_pd(dys[v * WIDTH..].as_mut_ptr(), dy[v]);
+    }
+
+    let mut orbits = [Orbit::default(); LANES];
+    for (lane, orbit) in orbits.iter_mut().enumerate() {
+        *orbit = Orbit {
+            iter: iters[lane] as u32,
+            x: xs[lane],
+            y: ys[lane],
+            dx: dxs[lane],
+            dy: dys[lane],
+            root: 0,
+            trap: Closest::default(),
+            average: Sum::default(),
+        };
+    }
+    orbits
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use crate::{Formula, Fractal, FORMULAS};
+
+    #[test]
+    fn avx2_lanes_match_the_scalar_kernel() {
+        if !is_x86_feature_detected!("avx2") {
+            return;
+        }
+
+        let bailout2 = 4.0;
+        //Grid over the set and the area around it, so there are orbits that escape straight
+        //away, late and never
+        let points: Vec<(f64, f64)> = (0..32)
+            .flat_map(|j| (0..32).map(move |i| (-2.2 + i as f64 * 0.11, -1.6 + j as f64 * 0.1)))
+            .collect();
+
+        for name in FORMULAS {
+            for fractal in [Fractal::Mandelbrot, Fractal::Julia(-0.8, 0.156)] {
+                for distance in [false, true] {
+                    for interior_check in [false, true] {
+                        let options = Options {
+                            max_iter: 200,
+                            formula: Formula::from_name(name).unwrap(),
+                            fractal,
+                            distance,
+                            interior_check,
+                            ..Options::default()
+                        };
+
+                        for batch in points.chunks(LANES) {
+                            let x0: [f64; LANES] = std::array::from_fn(|lane| batch[lane].0);
+                            let y0: [f64; LANES] = std::array::from_fn(|lane| batch[lane].1);
+                            let lanes = iterate_lanes(&options, x0, y0, bailout2);
+                            for lane in 0..LANES {
+                                let scalar = iterate(&options, x0[lane], y0[lane], bailout2);
+                                let simd = lanes[lane];
+                                let context = format!(
+                                    "{} {:?} distance {} interior check {} at ({}, {})",
+                                    name, fractal, distance, interior_check, x0[lane], y0[lane]
+                                );
+                                assert_eq!(simd.iter, scalar.iter, "{}", context);
+                                assert_eq!(simd.x.to_bits(), scalar.x.to_bits(), "{}", context);
+                                assert_eq!(simd.y.to_bits(), scalar.y.to_bits(), "{}", context);
+                                assert_eq!(simd.dx.to_bits(), scalar.dx.to_bits(), "{}", context);
+                                assert_eq!(simd.dy.to_bits(), scalar.dy.to_bits(), "{}", context);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
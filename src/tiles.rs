@@ -0,0 +1,131 @@
+//Tile scheduler for the cpu kernels. The image is cut into rectangular tiles which are handed
+//out through an atomic counter, and every worker writes its tiles straight into the field
+use crate::{EscapeData, IterationField};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Copy, Clone, Debug)]
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+pub struct TileScheduler<'a> {
+    tiles: Vec<Tile>,
+    next: AtomicUsize,
+    completed: AtomicUsize,
+    width: u32,
+    //Pixels to render, everything when there is no mask
+    mask: Option<Vec<bool>>,
+    data: *mut EscapeData,
+    owner: *mut u32,
+    _field: PhantomData<&'a mut IterationField>,
+}
+
+//Each tile is only handed out once and the tiles don't overlap, so no two threads can ever
+//write the same pixel, and the field stays borrowed for as long as the scheduler is alive
+unsafe impl Send for TileScheduler<'_> {}
+unsafe impl Sync for TileScheduler<'_> {}
+
+impl<'a> TileScheduler<'a> {
+    pub fn new(field: &'a mut IterationField, tile_size: u32) -> Self {
+        let tile_size = tile_size.max(1);
+        let mut tiles = Vec::new();
+        for y in (0..field.height).step_by(tile_size as usize) {
+            for x in (0..field.width).step_by(tile_size as usize) {
+                tiles.push(Tile {
+                    x,
+                    y,
+                    width: tile_size.min(field.width - x),
+                    height: tile_size.min(field.height - y),
+                });
+            }
+        }
+
+        Self {
+            tiles,
+            next: AtomicUsize::new(0),
+            completed: AtomicUsize::new(0),
+            width: field.width,
+            mask: None,
+            data: field.data.as_mut_ptr(),
+            owner: field.owner.as_mut_ptr(),
+            _field: PhantomData,
+        }
+    }
+
+    //Only hand out the pixels set in mask, tiles without any of them are skipped entirely
+    pub fn with_mask(field: &'a mut IterationField, tile_size: u32, mask: Vec<bool>) -> Self {
+        let mut scheduler = Self::new(field, tile_size);
+        let width = scheduler.width;
+        scheduler.tiles.retain(|tile| {
+            (tile.y..tile.y + tile.height)
+                .any(|y| (tile.x..tile.x + tile.width).any(|x| mask[(y * width + x) as usize]))
+        });
+        scheduler.mask = Some(mask);
+        scheduler
+    }
+
+    pub fn len(&self) -> usize {
+        self.tiles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiles.is_empty()
+    }
+
+    //Number of tiles that have been fully written, for progress reporting
+    pub fn completed(&self) -> usize {
+        self.completed.load(Ordering::Acquire)
+    }
+
+    //Claim the next tile, None once every tile has been handed out
+    pub fn next_tile(&self) -> Option<TileWriter<'_, 'a>> {
+        let index = self.next.fetch_add(1, Ordering::Relaxed);
+        self.tiles.get(index).map(|&tile| TileWriter {
+            tile,
+            scheduler: self,
+        })
+    }
+}
+
+//Write access to the pixels of one tile, the tile counts as completed when this is dropped
+pub struct TileWriter<'s, 'a> {
+    pub tile: Tile,
+    scheduler: &'s TileScheduler<'a>,
+}
+
+impl TileWriter<'_, '_> {
+    //Whether only some of the pixels in the tile are wanted
+    pub fn masked(&self) -> bool {
+        self.scheduler.mask.is_some()
+    }
+
+    //Whether the pixel at x, y needs rendering at all
+    pub fn wanted(&self, x: u32, y: u32) -> bool {
+        match &self.scheduler.mask {
+            Some(mask) => mask[(y * self.scheduler.width + x) as usize],
+            None => true,
+        }
+    }
+
+    //Store a pixel, x and y are in image coordinates and must lie inside the tile
+    pub fn set(&mut self, x: u32, y: u32, thread_id: u32, data: EscapeData) {
+        let tile = &self.tile;
+        assert!(x >= tile.x && x < tile.x + tile.width && y >= tile.y && y < tile.y + tile.height);
+
+        let index = (y * self.scheduler.width + x) as usize;
+        unsafe {
+            *self.scheduler.data.add(index) = data;
+            *self.scheduler.owner.add(index) = thread_id;
+        }
+    }
+}
+
+impl Drop for TileWriter<'_, '_> {
+    fn drop(&mut self) {
+        self.scheduler.completed.fetch_add(1, Ordering::Release);
+    }
+}
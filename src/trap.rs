@@ -0,0 +1,160 @@
+//Orbit traps. Instead of when it escapes, a pixel is coloured by how close its orbit came to a
+//shape, or by the part of an image it came closest to. Every point of the orbit after the
+//first iteration is checked so pixels inside the set get a colour too
+use image::{ImageResult, RgbImage};
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+pub const TRAPS: [&str; 5] = ["point", "line", "cross", "circle", "texture"];
+pub const DEFAULT_TRAP_ANGLE: f64 = 0.0;
+pub const DEFAULT_TRAP_SIZE: f64 = 1.0;
+
+#[derive(Clone, Debug)]
+pub enum Shape {
+    Point,
+    //Through the centre at angle degrees from the real axis, sin and cos are of the angle
+    Line {
+        angle: f64,
+        sin: f64,
+        cos: f64,
+    },
+    //Horizontal and vertical lines through the centre
+    Cross,
+    //Radius
+    Circle(f64),
+    //Square image of side size, distance is measured to the square so the first point of the
+    //orbit to land on the image is the one that picks its colour
+    Texture {
+        path: String,
+        image: Arc<RgbImage>,
+        size: f64,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct Trap {
+    pub shape: Shape,
+    pub x: f64,
+    pub y: f64,
+}
+
+impl Trap {
+    //The shapes that are only geometry, None for texture or a name that isn't in TRAPS. size is
+    //the radius of a circle
+    pub fn from_name(name: &str, x: f64, y: f64, angle: f64, size: f64) -> Option<Self> {
+        let shape = match name {
+            "point" => Shape::Point,
+            "line" => {
+                let (sin, cos) = angle.to_radians().sin_cos();
+                Shape::Line { angle, sin, cos }
+            }
+            "cross" => Shape::Cross,
+            "circle" => Shape::Circle(size),
+            _ => return None,
+        };
+        Some(Self { shape, x, y })
+    }
+
+    //Image trap of side size centred on (x, y), any format the image crate can read
+    pub fn texture(path: &str, x: f64, y: f64, size: f64) -> ImageResult<Self> {
+        let image = image::open(Path::new(path))?.to_rgb8();
+        Ok(Self {
+            shape: Shape::Texture {
+                path: path.to_string(),
+                image: Arc::new(image),
+                size,
+            },
+            x,
+            y,
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self.shape {
+            Shape::Point => "point",
+            Shape::Line { .. } => "line",
+            Shape::Cross => "cross",
+            Shape::Circle(_) => "circle",
+            Shape::Texture { .. } => "texture",
+        }
+    }
+
+    //Distance from the trap of a point given relative to its centre
+    #[inline(always)]
+    fn distance(&self, x: f64, y: f64) -> f64 {
+        match self.shape {
+            Shape::Point => (x * x + y * y).sqrt(),
+            Shape::Line { sin, cos, .. } => (x * sin - y * cos).abs(),
+            Shape::Cross => x.abs().min(y.abs()),
+            Shape::Circle(radius) => ((x * x + y * y).sqrt() - radius).abs(),
+            Shape::Texture { size, .. } => (x.abs().max(y.abs()) - size * 0.5).max(0.0),
+        }
+    }
+
+    //Colour of the image at a point relative to the centre, None for shapes without an image
+    //or points that are off it
+    pub fn texel(&self, x: f64, y: f64) -> Option<[u8; 3]> {
+        match &self.shape {
+            Shape::Texture { image, size, .. } => {
+                //Image rows go down the same way as the rows of the render
+                let u = x / size + 0.5;
+                let v = y / size + 0.5;
+                if !(0.0..1.0).contains(&u) || !(0.0..1.0).contains(&v) {
+                    return None;
+                }
+                let px = (u * image.width() as f64) as u32;
+                let py = (v * image.height() as f64) as u32;
+                Some(image.get_pixel(px, py).0)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.shape {
+            Shape::Point | Shape::Cross => write!(f, "{} ({}, {})", self.name(), self.x, self.y),
+            Shape::Line { angle, .. } => write!(f, "line ({}, {}) angle {}", self.x, self.y, angle),
+            Shape::Circle(radius) => {
+                write!(f, "circle ({}, {}) radius {}", self.x, self.y, radius)
+            }
+            Shape::Texture { path, size, .. } => {
+                write!(f, "texture {} ({}, {}) size {}", path, self.x, self.y, size)
+            }
+        }
+    }
+}
+
+//Closest an orbit has come to the trap so far and where that was, relative to the centre
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Closest {
+    pub distance: f64,
+    pub x: f64,
+    pub y: f64,
+}
+
+//Nowhere near, for orbits that escape before a point is checked
+impl Default for Closest {
+    fn default() -> Self {
+        Self {
+            distance: f64::INFINITY,
+            x: f64::INFINITY,
+            y: f64::INFINITY,
+        }
+    }
+}
+
+impl Closest {
+    //Ties keep the earlier point
+    #[inline(always)]
+    pub fn update(&mut self, trap: &Trap, x: f64, y: f64) {
+        let x = x - trap.x;
+        let y = y - trap.y;
+        let distance = trap.distance(x, y);
+        if distance < self.distance {
+            *self = Self { distance, x, y };
+        }
+    }
+}